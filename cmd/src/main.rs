@@ -1,99 +1,295 @@
-use std::env;
+use common::graph::*;
+use common::transitive_closure::TransitiveClosure;
 use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fs::read_to_string;
 use std::process::exit;
 use std::time::Instant;
-use common::graph::*;
-use common::transitive_closure::TransitiveClosure;
+use ustr::{Ustr, UstrSet};
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Fetch `args[i]`, or print a usage error and exit(1) instead of panicking on an
+/// out-of-bounds index, for subcommands that require a trailing argument
+fn require_arg<'a>(args: &'a [String], i: usize, subcommand: &str) -> &'a str {
+    match args.get(i) {
+        Some(a) => a.as_str(),
+        None => {
+            eprintln!("{}: missing required argument", subcommand);
+            exit(1);
+        }
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
+        eprintln!(
+            "usage: {} [--format text|json] <subcommand> [args...] [<subcommand> [args...] ...]",
+            args.first().map(String::as_str).unwrap_or("prunepytest"),
+        );
+        eprintln!("subcommands: parse, save, load, dump, affected, depends-on, entry-point-depends-on, cycles");
         exit(1);
     }
 
+    let mut format = OutputFormat::Text;
     let mut g: Option<TransitiveClosure> = None;
+    // only set by `parse`, mirroring `pyext::ModuleGraph`'s `g` field: holds onto the
+    // pre-finalize graph so a later `cycles` subcommand still has `global_ns` to walk
+    let mut module_graph: Option<ModuleGraph> = None;
 
-    for mut i in 1..args.len() {
+    let mut i = 1;
+    while i < args.len() {
         let start = Instant::now();
-        if &args[i] == "--parse" && i+1 < args.len() {
-            i += 1;
-            let mut packages: HashMap<String, String> = HashMap::new();
-
-            if args[i].starts_with('@') {
-                for line in read_to_string(&args[i][1..]).unwrap().split('\n') {
-                    let (py_path, fs_path) = line.split_once(':').unwrap();
-                    packages.insert(py_path.to_string(), fs_path.to_string());
-                }
-            } else {
-                packages.extend(
-                    args[i].split(',').map(
-                        |s| {
-                            let (a, b) = s.rsplit_once(':').unwrap();
-                            (a.to_string(), b.to_string())
-                        }
-                    )
-                )
+        match args[i].as_str() {
+            "--format" => {
+                i += 1;
+                format = match args.get(i).map(String::as_str) {
+                    Some("text") => OutputFormat::Text,
+                    Some("json") => OutputFormat::Json,
+                    other => {
+                        eprintln!("unknown --format value: {:?}", other);
+                        exit(1);
+                    }
+                };
+                i += 1;
             }
+            "parse" => {
+                i += 1;
+                let mut packages: HashMap<String, String> = HashMap::new();
+                let arg = require_arg(&args, i, "parse");
 
-            eprintln!("building module graph for {} packages", packages.len());
+                if arg.starts_with('@') {
+                    for line in read_to_string(&arg[1..]).unwrap().split('\n') {
+                        let (py_path, fs_path) = line.split_once(':').unwrap();
+                        packages.insert(py_path.to_string(), fs_path.to_string());
+                    }
+                } else {
+                    packages.extend(arg.split(',').map(|s| {
+                        let (a, b) = s.rsplit_once(':').unwrap();
+                        (a.to_string(), b.to_string())
+                    }))
+                }
+                i += 1;
+
+                eprintln!("building module graph for {} packages", packages.len());
 
-            let module_graph = ModuleGraph::new(
-                packages,
-                HashSet::from_iter(["affirm".to_string()]),
-                HashSet::from_iter(["tests".to_string()]),
-                HashSet::new(),
-            );
+                let graph = ModuleGraph::new(
+                    packages,
+                    HashSet::from_iter(["affirm".to_string()]),
+                    HashSet::from_iter(["tests".to_string()]),
+                    HashSet::new(),
+                    common::stdlib::list_stdlib_modules(None),
+                );
 
-            module_graph.parse_parallel().expect("failed to parse module graph");
+                graph
+                    .parse_parallel(false)
+                    .expect("failed to parse module graph");
 
-            eprintln!("built: {}",
-                      Instant::now().duration_since(start).as_millis());
+                eprintln!("built: {}", Instant::now().duration_since(start).as_millis());
 
-            let tc = module_graph.finalize();
+                // snapshot rather than finalize: keep `graph` around for `cycles`
+                let tc = graph.snapshot();
 
-            eprintln!("finalized {}",
-                      Instant::now().duration_since(start).as_millis());
-            g.replace(tc);
-        } else if &args[i] == "--dump" && i+1 < args.len() {
-            i += 1;
-            if let Some(mg) = g.as_ref() {
-                mg.to_small_text_file(&args[i])
-                    .expect("failed to dump module graph");
+                eprintln!(
+                    "finalized: {}",
+                    Instant::now().duration_since(start).as_millis()
+                );
+                g.replace(tc);
+                module_graph.replace(graph);
             }
-            eprintln!("written out {}",
-                      Instant::now().duration_since(start).as_millis());
-        }  else if &args[i] == "--save" && i+1 < args.len() {
-            i += 1;
-            if let Some(mg) = g.as_ref() {
-                mg.to_file(&args[i])
-                    .expect("failed to serialize module graph");
+            "dump" => {
+                i += 1;
+                let arg = require_arg(&args, i, "dump");
+                if let Some(tc) = g.as_ref() {
+                    tc.to_small_text_file(arg)
+                        .expect("failed to dump module graph");
+                }
+                i += 1;
+                eprintln!(
+                    "written out: {}",
+                    Instant::now().duration_since(start).as_millis()
+                );
             }
-            eprintln!("written out {}",
-                      Instant::now().duration_since(start).as_millis());
-        } else if &args[i] == "--load" && i+1 < args.len() {
-            i += 1;
-            g.replace(TransitiveClosure::from_file(&args[i])
-                .expect("failed to deserialize module graph"));
-            eprintln!("reloaded {}",
-                      Instant::now().duration_since(start).as_millis());
-        } else if &args[i] == "--affected" {
-            i += 1;
-            let affected = g.as_ref().unwrap().affected_by(&args[i..i+1]);
-            eprintln!("affected by {}:", &args[i]);
-            for (pkg, files) in &affected {
-                eprintln!("  - {}:", pkg);
-                for f in files {
-                    eprintln!("      - {}", f);
+            "save" => {
+                i += 1;
+                let arg = require_arg(&args, i, "save");
+                if let Some(tc) = g.as_ref() {
+                    tc.to_file(arg).expect("failed to serialize module graph");
                 }
+                i += 1;
+                eprintln!(
+                    "written out: {}",
+                    Instant::now().duration_since(start).as_millis()
+                );
+            }
+            "load" => {
+                i += 1;
+                let arg = require_arg(&args, i, "load");
+                g.replace(
+                    TransitiveClosure::from_file(arg)
+                        .expect("failed to deserialize module graph"),
+                );
+                i += 1;
+                eprintln!(
+                    "reloaded: {}",
+                    Instant::now().duration_since(start).as_millis()
+                );
+            }
+            "affected" => {
+                i += 1;
+                let arg = require_arg(&args, i, "affected");
+                let affected = g
+                    .as_ref()
+                    .expect("affected requires a graph: run parse/load first")
+                    .local_affected_by_files(&[arg.to_string()]);
+                i += 1;
+                print_affected(&affected, format);
+            }
+            "depends-on" => {
+                i += 1;
+                let module = require_arg(&args, i, "depends-on");
+                let deps = g
+                    .as_ref()
+                    .expect("depends-on requires a graph: run parse/load first")
+                    .module_depends_on(module, None);
+                i += 1;
+                print_depends_on(module, &deps, format);
+            }
+            "entry-point-depends-on" => {
+                i += 1;
+                let pkg = require_arg(&args, i, "entry-point-depends-on");
+                let deps = g
+                    .as_ref()
+                    .expect("entry-point-depends-on requires a graph: run parse/load first")
+                    .entry_point_depends_on(pkg);
+                i += 1;
+                print_depends_on(pkg, &deps, format);
+            }
+            "cycles" => {
+                i += 1;
+                let cycles = module_graph
+                    .as_ref()
+                    .expect("cycles requires a graph built via parse, not one loaded from a file")
+                    .find_import_cycles();
+                print_cycles(&cycles, format);
+            }
+            other => {
+                eprintln!("unknown subcommand: {}", other);
+                exit(1);
             }
-        } else if &args[i] == "--affected" {
-            i += 1;
-            let deps = g.as_ref().unwrap().module_depends_on(&args[i], None);
-            eprintln!("depends on {}: {:?}", &args[i], deps);
         }
     }
 
     exit(0);
 }
+
+/// Mirrors `ModuleGraph.local_affected_by_files`/`local_affected_by_modules`: package
+/// root -> sorted list of affected test files, so `--format json` output is a drop-in
+/// match for the pyclass's own structured accessors
+fn print_affected(affected: &HashMap<Ustr, UstrSet>, format: OutputFormat) {
+    let mut pkgs: Vec<&Ustr> = affected.keys().collect();
+    pkgs.sort();
+
+    match format {
+        OutputFormat::Json => {
+            let entries: Vec<String> = pkgs
+                .iter()
+                .map(|&pkg| {
+                    let mut files: Vec<&Ustr> = affected[pkg].iter().collect();
+                    files.sort();
+                    let files_json = files
+                        .iter()
+                        .map(|f| format!("\"{}\"", json_escape(f.as_str())))
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    format!("\"{}\":[{}]", json_escape(pkg.as_str()), files_json)
+                })
+                .collect();
+            println!("{{{}}}", entries.join(","));
+        }
+        OutputFormat::Text => {
+            for &pkg in &pkgs {
+                println!("{}:", pkg);
+                let mut files: Vec<&Ustr> = affected[pkg].iter().collect();
+                files.sort();
+                for f in files {
+                    println!("  {}", f);
+                }
+            }
+        }
+    }
+}
+
+fn print_depends_on(module: &str, deps: &Option<HashSet<Ustr>>, format: OutputFormat) {
+    let Some(deps) = deps else {
+        match format {
+            OutputFormat::Json => println!("null"),
+            OutputFormat::Text => println!("{}: <unresolved>", module),
+        }
+        return;
+    };
+
+    let mut sorted: Vec<&Ustr> = deps.iter().collect();
+    sorted.sort();
+
+    match format {
+        OutputFormat::Json => {
+            let items: Vec<String> = sorted
+                .iter()
+                .map(|d| format!("\"{}\"", json_escape(d.as_str())))
+                .collect();
+            println!("[{}]", items.join(","));
+        }
+        OutputFormat::Text => {
+            println!("{}:", module);
+            for d in sorted {
+                println!("  {}", d);
+            }
+        }
+    }
+}
+
+fn print_cycles(cycles: &[Vec<String>], format: OutputFormat) {
+    match format {
+        OutputFormat::Json => {
+            let entries: Vec<String> = cycles
+                .iter()
+                .map(|cycle| {
+                    let items: Vec<String> = cycle
+                        .iter()
+                        .map(|m| format!("\"{}\"", json_escape(m)))
+                        .collect();
+                    format!("[{}]", items.join(","))
+                })
+                .collect();
+            println!("[{}]", entries.join(","));
+        }
+        OutputFormat::Text => {
+            for cycle in cycles {
+                println!("{}", cycle.join(" -> "));
+            }
+        }
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,220 @@
+// SPDX-FileCopyrightText: © 2024 Hugues Bruant <hugues.bruant@gmail.com>
+
+// Validates whether a previously persisted `TransitiveClosure` is still accurate for
+// the current state of the source tree, so a full rebuild can be skipped entirely
+// when nothing relevant has changed. Modeled on Mercurial's persistent nodemap: a
+// small "docket" header records what the cached data was built from, and is cheap to
+// re-validate against the live filesystem before trusting the (much larger) cached
+// blob that sits next to it.
+
+use crate::moduleref::{read_ustr_with_buf, write_ustr_to};
+use ignore::WalkBuilder;
+use speedy::private::{read_length_u64_varint, write_length_u64_varint};
+use speedy::{Context, Error, LittleEndian, Readable, Reader, Writable, Writer};
+use std::collections::HashMap;
+use std::fs::File;
+use std::time::UNIX_EPOCH;
+use ustr::{ustr, Ustr};
+
+// extensions `ModuleGraph::stub_for` treats as possible source of dependency
+// information; kept in sync with it so a freshly added file of one of these
+// kinds is enough to flag the docket stale, without having to duplicate its
+// full stub-precedence logic here
+const TRACKED_EXTENSIONS: [&str; 4] = ["py", "pyi", "pyx", "pxd"];
+
+// bump whenever the shape of `Docket` (or the `TransitiveClosure` format it vouches
+// for) changes in an incompatible way, so a stale cache is rejected outright
+// rather than misread
+const DOCKET_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileFingerprint {
+    mtime_nanos: u64,
+    size: u64,
+}
+
+impl FileFingerprint {
+    fn of(path: &str) -> Option<FileFingerprint> {
+        let meta = std::fs::metadata(path).ok()?;
+        let mtime_nanos = meta
+            .modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_nanos() as u64;
+        Some(FileFingerprint {
+            mtime_nanos,
+            size: meta.len(),
+        })
+    }
+}
+
+/// A small header proving (or disproving) that a persisted `TransitiveClosure` still
+/// matches the source tree it was built from, so the expensive parse + finalize pass
+/// can be skipped on an unchanged tree.
+pub struct Docket {
+    format_version: u32,
+    // search roots the docket was built from; a change here (a package added,
+    // removed, or remapped) invalidates the cache outright, since it can shift
+    // which files should even be scanned
+    roots: HashMap<Ustr, Ustr>,
+    files: HashMap<Ustr, FileFingerprint>,
+}
+
+impl Docket {
+    /// Snapshot the current mtime+size of every file in `files`, alongside `roots`,
+    /// the source-root map the graph was built from
+    pub fn build<'a>(
+        roots: &HashMap<String, String>,
+        files: impl IntoIterator<Item = &'a str>,
+    ) -> Docket {
+        let mut fingerprints = HashMap::new();
+        for f in files {
+            if let Some(fp) = FileFingerprint::of(f) {
+                fingerprints.insert(ustr(f), fp);
+            }
+        }
+        Docket {
+            format_version: DOCKET_FORMAT_VERSION,
+            roots: roots
+                .iter()
+                .map(|(k, v)| (ustr(k), ustr(v)))
+                .collect(),
+            files: fingerprints,
+        }
+    }
+
+    /// Whether every file recorded here still has the same size and mtime on disk,
+    /// `roots` hasn't changed since, and no new trackable file has appeared under any
+    /// of `roots`. `false` means the caller must fall back to a full rebuild: either
+    /// something moved/appeared under us, or the docket itself no longer matches the
+    /// requested configuration.
+    pub fn is_valid(&self, roots: &HashMap<String, String>) -> bool {
+        if self.format_version != DOCKET_FORMAT_VERSION {
+            return false;
+        }
+        if self.roots.len() != roots.len()
+            || roots
+                .iter()
+                .any(|(k, v)| self.roots.get(&ustr(k)) != Some(&ustr(v)))
+        {
+            return false;
+        }
+        if !self
+            .files
+            .iter()
+            .all(|(path, fp)| FileFingerprint::of(path) == Some(*fp))
+        {
+            return false;
+        }
+        // re-stating known files only catches mutation/removal: a brand-new file
+        // dropped in under a tracked root wouldn't show up in `self.files` at all,
+        // so walk the roots afresh and make sure nothing trackable is unaccounted for
+        !self.has_untracked_files(roots)
+    }
+
+    /// Whether a fresh walk of `roots` turns up any file of a `TRACKED_EXTENSIONS`
+    /// kind that isn't already a key in `self.files`
+    fn has_untracked_files(&self, roots: &HashMap<String, String>) -> bool {
+        let mut it = roots.keys();
+        let Some(first) = it.next() else {
+            return false;
+        };
+        let mut builder = WalkBuilder::new(first);
+        for root in it {
+            builder.add(root);
+        }
+        builder.standard_filters(false).hidden(true);
+
+        for entry in builder.build() {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                continue;
+            }
+            let path = match entry.path().to_str() {
+                Some(p) => p,
+                None => continue,
+            };
+            let is_tracked_ext = TRACKED_EXTENSIONS
+                .iter()
+                .any(|ext| path.strip_suffix(ext).is_some_and(|p| p.ends_with('.')));
+            if is_tracked_ext && !self.files.contains_key(&ustr(path)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn to_file(&self, filepath: &str) -> Result<(), Error> {
+        let file = File::create(filepath).map_err(|e| Error::custom(e.to_string()))?;
+        let stream = zstd::Encoder::new(file, 0)
+            .map_err(|e| Error::custom(e.to_string()))?
+            .auto_finish();
+        self.write_to_stream_with_ctx(LittleEndian::default(), stream)
+    }
+
+    pub fn from_file(filepath: &str) -> Result<Docket, Error> {
+        let file = File::open(filepath).map_err(|e| Error::custom(e.to_string()))?;
+        let stream = zstd::Decoder::new(file).map_err(|e| Error::custom(e.to_string()))?;
+        Self::read_from_stream_buffered_with_ctx(LittleEndian::default(), stream)
+    }
+}
+
+impl<C> Writable<C> for Docket
+where
+    C: Context,
+{
+    fn write_to<T: ?Sized + Writer<C>>(&self, w: &mut T) -> Result<(), C::Error> {
+        w.write_u64_varint(self.format_version as u64)?;
+
+        write_length_u64_varint(self.roots.len(), w)?;
+        for (fs_root, py_root) in &self.roots {
+            write_ustr_to(*fs_root, w)?;
+            write_ustr_to(*py_root, w)?;
+        }
+
+        write_length_u64_varint(self.files.len(), w)?;
+        for (path, fp) in &self.files {
+            write_ustr_to(*path, w)?;
+            w.write_u64_varint(fp.mtime_nanos)?;
+            w.write_u64_varint(fp.size)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, C> Readable<'a, C> for Docket
+where
+    C: Context,
+{
+    fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
+        let format_version = reader.read_u64_varint()? as u32;
+
+        let mut buf: Vec<u8> = Vec::new();
+        let n = read_length_u64_varint(reader)?;
+        let mut roots = HashMap::with_capacity(n);
+        for _ in 0..n {
+            let fs_root = read_ustr_with_buf(reader, &mut buf)?;
+            let py_root = read_ustr_with_buf(reader, &mut buf)?;
+            roots.insert(fs_root, py_root);
+        }
+
+        let n = read_length_u64_varint(reader)?;
+        let mut files = HashMap::with_capacity(n);
+        for _ in 0..n {
+            let path = read_ustr_with_buf(reader, &mut buf)?;
+            let mtime_nanos = reader.read_u64_varint()?;
+            let size = reader.read_u64_varint()?;
+            files.insert(path, FileFingerprint { mtime_nanos, size });
+        }
+
+        Ok(Docket {
+            format_version,
+            roots,
+            files,
+        })
+    }
+}
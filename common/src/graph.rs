@@ -1,8 +1,12 @@
 // SPDX-FileCopyrightText: © 2024 Hugues Bruant <hugues.bruant@gmail.com>
 
+use crate::docket::Docket;
 use crate::matcher::MatcherNode;
-use crate::moduleref::{LockedModuleRefCache, ModuleRef, ModuleRefCache};
-use crate::parser::raw_get_all_imports;
+use crate::moduleref::{LockedModuleRefCache, ModuleRef, ModuleRefCache, ModuleTrie};
+use crate::parser::{
+    dir_looks_like_pep420_namespace, raw_get_all_imports, split_at_depth, ImportKind,
+    ImportRecord, NamespaceKind,
+};
 use crate::transitive_closure::TransitiveClosure;
 use anyhow::Context;
 use dashmap::{DashMap, Entry};
@@ -26,6 +30,12 @@ pub struct ModuleGraph {
     // most useful to track importlib and __import___
     external_prefixes: MatcherNode,
 
+    // auto-detected standard-library top-level modules (see `crate::stdlib`),
+    // treated like `external_prefixes` so that `os`, `json`, etc. resolve to a
+    // tracked-but-external node instead of being silently dropped or flagged
+    // as unresolved
+    stdlib_matcher: MatcherNode,
+
     // prefix matching for package import/package paths
     import_matcher: MatcherNode,
     package_matcher: MatcherNode,
@@ -36,6 +46,22 @@ pub struct ModuleGraph {
     // collected imports
     global_ns: DashMap<ModuleRef, HashSet<ModuleRef>>,
     unresolved: DashMap<Ustr, HashSet<ModuleRef>>,
+
+    // statically-evaluated `__all__` of a module, when determinable (see
+    // `parser::AllState`), keyed by that module's own ModuleRef
+    dunder_all: DashMap<ModuleRef, Vec<String>>,
+    // `from pkg import *` targets collected during the (parallel) parse pass, keyed by
+    // the ModuleRef of the importing module. Resolution is deferred to
+    // `resolve_pending_stars`, run once the whole tree has been parsed, because the
+    // target package's `__init__.py` (and thus its `__all__`) might not have been
+    // parsed yet when the star-import itself is encountered
+    pending_stars: DashMap<ModuleRef, Vec<(Ustr, String)>>,
+
+    // `__main__.py` entry points, keyed by the import path of the package they belong
+    // to (empty string for a top-level script), so `python -m pkg`'s dependency
+    // footprint can be queried as a first-class target rather than an ordinary,
+    // un-distinguished submodule
+    entry_points: DashMap<Ustr, ModuleRef>,
 }
 
 fn root_namespace(name: &str) -> &str {
@@ -45,12 +71,27 @@ fn root_namespace(name: &str) -> &str {
     }
 }
 
+/// Per-node edge delta produced by `ModuleGraph::update_files`: which direct
+/// dependencies were gained or lost, and which modules disappeared entirely, so
+/// `TransitiveClosure::apply_update` can mend the condensed closure incrementally
+/// instead of rebuilding it from scratch. Also carries a fresh snapshot of
+/// `unresolved`, since re-parsing a file can just as easily fix a previously
+/// unresolved import as break a previously resolved one
+#[derive(Debug, Default)]
+pub struct GraphEdgeDiff {
+    pub(crate) inserted: Vec<(ModuleRef, ModuleRef)>,
+    pub(crate) removed: Vec<(ModuleRef, ModuleRef)>,
+    pub(crate) removed_nodes: Vec<ModuleRef>,
+    pub(crate) unresolved: HashMap<Ustr, HashSet<ModuleRef>>,
+}
+
 impl ModuleGraph {
     pub fn new(
         source_roots: HashMap<String, String>,
         global_prefixes: HashSet<String>,
         local_prefixes: HashSet<String>,
         external_prefixes: HashSet<String>,
+        stdlib_modules: HashSet<String>,
     ) -> ModuleGraph {
         ModuleGraph {
             // NB: exclude local ns from import matcher
@@ -73,10 +114,14 @@ impl ModuleGraph {
             global_prefixes,
             local_prefixes,
             external_prefixes: MatcherNode::from(external_prefixes, '.'),
+            stdlib_matcher: MatcherNode::from(stdlib_modules, '.'),
             modules_refs: LockedModuleRefCache::new(),
             dir_cache: DashMap::new(),
             global_ns: DashMap::new(),
             unresolved: DashMap::new(),
+            dunder_all: DashMap::new(),
+            pending_stars: DashMap::new(),
+            entry_points: DashMap::new(),
         }
     }
 
@@ -91,13 +136,35 @@ impl ModuleGraph {
         }
     }
 
-    pub fn add<T: IntoIterator<Item = String>>(
+    /// Resolve `dep` against the configured external prefixes, falling back to the
+    /// stdlib matcher, and return a single shared `ModuleRef` for the matched prefix
+    /// if either one matches. Used both for plain imports and for the package prefix
+    /// of a star-import, which is never itself expanded past its external/stdlib root
+    fn external_or_stdlib_ref(&self, dep: &str) -> Option<ModuleRef> {
+        let pref = self.external_prefixes.longest_prefix(dep, '.');
+        let pref = if pref.is_empty() {
+            // not an explicitly configured external prefix, but it might
+            // still be a recognized stdlib module, which we track the
+            // same way rather than silently dropping or flagging unresolved
+            self.stdlib_matcher.longest_prefix(dep, '.')
+        } else {
+            pref
+        };
+        if pref.is_empty() {
+            None
+        } else {
+            Some(self.modules_refs.get_or_create(ustr(""), ustr(pref), None))
+        }
+    }
+
+    pub fn add<T: IntoIterator<Item = ImportRecord>>(
         &self,
         filepath: &str,
         pkg: &str,
         module: &str,
         deps: T,
-        is_ns_pkg_init: bool,
+        ns_kind: NamespaceKind,
+        dunder_all: Option<Vec<String>>,
     ) {
         let is_local = match self.is_local(module) {
             None => {
@@ -121,45 +188,34 @@ impl ModuleGraph {
 
         let mut unresolved = HashSet::new();
         let mut imports = HashSet::new();
+        let mut pending_stars: Vec<(Ustr, String)> = Vec::new();
 
         for dep in deps {
-            let pref = self.external_prefixes.longest_prefix(&dep, '.');
-            if !pref.is_empty() {
-                imports.insert(self.modules_refs.get_or_create(ustr(""), ustr(pref), None));
+            let optional = dep.optional;
+            let kind = dep.kind;
+            let dep = dep.name;
+            if let Some(r) = self.external_or_stdlib_ref(&dep) {
+                imports.insert(r);
                 continue;
             } else if dep.ends_with(".*") {
-                // NB: per python spec, star import only import submodules that are referenced in
-                // the __all__ variable set in a package's __init__.py
-                // Handling that accurately would require:
-                //  - evaluating __all__ which would necessarily have to rely on heuristics since
-                //    it could in theory be touched with arbitrary code, because that's how Python
-                //    rolls
-                //  - tracking the value of __all__ for all packages
-                //  - deferring resolution of * imports until the relevant package is parsed and
-                //    its __all__ value is known
-                //
-                // This is a tremendous amount of complexity for relatively little value. Instead,
-                // we can do something much easier: act as if __all__ contained all the submodules
-                // present on the filesystem.
-                // This might result in spurious additional dependencies, but it cannot possibly
-                // result in missed dependencies, and we're more concerned about false negatives
-                // than false positives.
-                // These "spurious" additional deps are in fact a feature, as it allows us to
-                // concisely inform the parser of some programmatically inserted dependencies
+                // per python spec, a star import only pulls in the submodules listed in
+                // the target package's __all__. We can't know that until the target's
+                // __init__.py has itself been parsed, which might not have happened yet
+                // (files are parsed in parallel, in no particular order), so resolution
+                // is deferred to `resolve_pending_stars`, once the whole tree is known.
+                // Stash the (importer's pkg, target) pair here for now
                 let target = &dep[..dep.len() - 2];
-                if let Some(refs) = self.to_module_list_local_aware(pkg, ustr(target)) {
-                    debug!("star: {} {} {:?}", filepath, dep, refs);
-                    refs.iter().for_each(|r| {
-                        imports.insert(*r);
-                    });
-                }
-            } else if let Some(dep_ref) = self.to_module_local_aware(pkg, ustr(&dep)) {
+                pending_stars.push((ustr(pkg), target.to_string()));
+            } else if let Some(dep_ref) = self.to_module_local_aware(pkg, ustr(&dep), kind) {
                 imports.insert(dep_ref);
-            } else if self.is_local(&dep).is_some() {
+            } else if !optional && self.is_local(&dep).is_some() {
                 // record relevant imports that cannot be resolved
                 // NB: if resolution failed, we know that we also fail to find the parent
                 // so record that, to reduce noise from many function/classes from a single
                 // unresolved module
+                // NB: imports guarded by try/except ImportError are optional: their absence
+                // is already handled by the code that guards them, so a missing optional
+                // import is not reported as unresolved
                 if let Some(idx) = dep.rfind('.') {
                     info!("unresolved: {} {} {}", filepath, dep, &dep[..idx]);
                     unresolved.insert(ustr(&dep[..idx]));
@@ -167,7 +223,7 @@ impl ModuleGraph {
             }
         }
 
-        let nspkg = is_ns_pkg_init || self.import_matcher.strict_prefix(module, '.');
+        let nspkg = ns_kind.is_namespace() || self.import_matcher.strict_prefix(module, '.');
         let module_ref = if nspkg && !is_local {
             // __init__.py for a namespace package
             // should be empty except for the '__path__ = ...' stanza
@@ -201,6 +257,22 @@ impl ModuleGraph {
         for un in unresolved {
             self.unresolved.entry(un).or_default().insert(module_ref);
         }
+        if let Some(all) = dunder_all {
+            self.dunder_all.insert(module_ref, all);
+        }
+        if !pending_stars.is_empty() {
+            self.pending_stars
+                .entry(module_ref)
+                .or_default()
+                .extend(pending_stars);
+        }
+        // `__main__.py` entry point: record which package (or the root, for a
+        // top-level script) it belongs to, so it can be looked up as a named target
+        if module == "__main__" {
+            self.entry_points.insert(ustr(""), module_ref);
+        } else if let Some(owner) = module.strip_suffix(".__main__") {
+            self.entry_points.insert(ustr(owner), module_ref);
+        }
         debug!(
             "parsed imports: {} {} {} {}",
             filepath,
@@ -269,6 +341,7 @@ impl ModuleGraph {
         mut dep: Ustr,
         fs_candidate: &str,
         local_fs_root: Option<Ustr>,
+        kind: ImportKind,
     ) -> Option<ModuleRef> {
         // the target of an import statement could be a module, or a value within that module
         // we only want to deal with modules when building an import graph, so we check if a
@@ -322,14 +395,17 @@ impl ModuleGraph {
             }
 
             // if at first you don't succeed remove the last component and try again
-            // TODO: for correctness we should distinguish between simple import and from import
-            // as this fallback is only valid for the latter...
-            if let Some(idx) = dep.rfind('.') {
-                depbase = depbase[..depbase.len() - dep.len() + idx].to_string();
-                dep = ustr(&dep[..idx]);
-            } else {
-                break;
+            // NB: only valid for `from a.b import c`, where `c` might be an attribute of
+            // `a.b` rather than a submodule; a plain `import a.b.c` requires the full
+            // dotted path to be a real module, so there is nothing to fall back to
+            if kind == ImportKind::From {
+                if let Some(idx) = dep.rfind('.') {
+                    depbase = depbase[..depbase.len() - dep.len() + idx].to_string();
+                    dep = ustr(&dep[..idx]);
+                    continue;
+                }
             }
+            break;
         }
         if let Some(_is_local) = self.is_local(dep.as_str()) {
             // TODO: would be nice to report where from
@@ -338,76 +414,83 @@ impl ModuleGraph {
         None
     }
 
-    fn to_module_local_aware(&self, fs_root: &str, dep: Ustr) -> Option<ModuleRef> {
+    fn to_module_local_aware(&self, fs_root: &str, dep: Ustr, kind: ImportKind) -> Option<ModuleRef> {
         if self.import_matcher.strict_prefix(dep.as_str(), '.') {
             // namespace packages FTW
             return Some(self.modules_refs.get_or_create(ustr(""), dep, None));
         }
         match self.py_to_fs(&dep, fs_root) {
-            Some((fs_cand, local_fs_root)) => self.to_module_no_cache(dep, &fs_cand, local_fs_root),
+            Some((fs_cand, local_fs_root)) => {
+                self.to_module_no_cache(dep, &fs_cand, local_fs_root, kind)
+            }
             None => None,
         }
     }
 
-    fn to_module_list(
-        &self,
-        fs_cand: String,
-        dep: Ustr,
-        local_fs_root: Option<Ustr>,
-    ) -> Option<Vec<ModuleRef>> {
-        let r = self.to_module_no_cache(dep, &fs_cand, local_fs_root);
-        match fs::read_dir(&fs_cand) {
-            Err(_) => r.map(|r| vec![r]),
-            Ok(entries) => Some(
-                entries
-                    .filter_map(|entry| match entry {
-                        Err(_) => None,
-                        Ok(e) => {
-                            let t = e.file_type().unwrap();
-                            let name = e.file_name().to_str().unwrap().to_string();
-                            if t.is_dir() {
-                                if fs::exists(e.path().join("__init__.py")).unwrap_or(false)
-                                    || fs::exists(e.path().join("__init__.pyi")).unwrap_or(false)
-                                    || fs::exists(e.path().join("__init__.pyx")).unwrap_or(false)
-                                {
-                                    Some(name)
-                                } else {
-                                    None
-                                }
-                            } else if !t.is_file() {
-                                None
-                            } else if name.ends_with(".py") && name != "__init__.py" {
-                                Some(name[..name.len() - 3].to_string())
-                            } else if (name.ends_with(".pyi") && name != "__init__.pyi")
-                                || (name.ends_with(".pyx") && name != "__init__.pyx")
-                            {
-                                // NB: there might be a duplicate if there is a matching *.py
-                                // however that will be fine downstream as we only ever use
-                                // this list to populate a set of ModuleRef...
-                                Some(name[..name.len() - 4].to_string())
-                            } else {
-                                None
-                            }
-                        }
-                    })
-                    .filter_map(|sub| {
-                        let subdep = dep.to_string() + "." + &sub;
-                        self.to_module_no_cache(
-                            ustr(&subdep),
-                            &(fs_cand.clone() + MAIN_SEPARATOR_STR + &sub),
-                            local_fs_root,
-                        )
-                    })
-                    .chain(r.map_or(Vec::default(), |r| vec![r]))
-                    .collect(),
-            ),
+    /// Resolve a single `from pkg import *` deferred during the parallel parse pass,
+    /// inserting the resulting `ModuleRef`s directly into `importer`'s entry in
+    /// `global_ns`
+    fn resolve_star(&self, importer: ModuleRef, pkg: &str, target: &str, trie: &ModuleTrie) {
+        if let Some(r) = self.external_or_stdlib_ref(target) {
+            self.global_ns.entry(importer).or_default().insert(r);
+            return;
+        }
+        let target_ref = self.to_module_local_aware(pkg, ustr(target), ImportKind::Plain);
+        let known_all = target_ref.and_then(|r| {
+            self.dunder_all
+                .get(&r)
+                .map(|names| names.value().clone())
+        });
+        match known_all {
+            Some(names) => {
+                debug!("star: {} {:?} (via __all__)", target, names);
+                let mut entry = self.global_ns.entry(importer).or_default();
+                for name in names {
+                    let dotted = target.to_string() + "." + &name;
+                    if let Some(r) = self.external_or_stdlib_ref(&dotted) {
+                        entry.insert(r);
+                    } else if let Some(r) =
+                        self.to_module_local_aware(pkg, ustr(&dotted), ImportKind::From)
+                    {
+                        entry.insert(r);
+                    }
+                }
+            }
+            None => {
+                // no statically known __all__: fall back to treating every direct
+                // submodule of the target package as if it were listed in __all__,
+                // reusing the same prefix-match trie that backs `add_dynamic_dep`'s
+                // `pkg.*` wildcard edges so static and dynamic wildcards converge on
+                // one implementation. This might result in spurious additional
+                // dependencies, but it cannot possibly result in missed dependencies,
+                // and we're more concerned about false negatives than false positives
+                let submodules = trie.direct_children(target);
+                debug!("star: {} {:?} (via submodule trie)", target, submodules);
+                let mut entry = self.global_ns.entry(importer).or_default();
+                for r in submodules {
+                    entry.insert(r);
+                }
+                if let Some(r) = target_ref {
+                    entry.insert(r);
+                }
+            }
         }
     }
 
-    fn to_module_list_local_aware(&self, pkg: &str, dep: Ustr) -> Option<Vec<ModuleRef>> {
-        match self.py_to_fs(&dep, pkg) {
-            Some((fs_cand, local_fs_root)) => self.to_module_list(fs_cand, dep, local_fs_root),
-            None => None,
+    /// Resolve every `from pkg import *` collected during the parallel parse pass.
+    /// Deferred until now because the target package's `__init__.py` (and thus its
+    /// statically evaluated `__all__`) might not have been parsed yet at the point
+    /// the star-import itself was encountered
+    fn resolve_pending_stars(&self) {
+        // snapshot the module trie once up front, same rationale as
+        // `add_dynamic_dependencies`: every pending star potentially needs a
+        // submodule lookup, so it's cheaper to build this once than per-star
+        let trie = self.modules_refs.module_trie();
+        for entry in self.pending_stars.iter() {
+            let importer = *entry.key();
+            for (pkg, target) in entry.value() {
+                self.resolve_star(importer, pkg, target, &trie);
+            }
         }
     }
 
@@ -468,6 +551,7 @@ impl ModuleGraph {
                 res = Err(err);
             }
         }
+        self.resolve_pending_stars();
         res
     }
 
@@ -528,15 +612,18 @@ impl ModuleGraph {
         }
     }
 
-    fn parse_one_file(
-        &self,
-        e: DirEntry,
-        include_typechecking: bool,
-        _tx: &mpsc::Sender<anyhow::Error>,
-    ) -> WalkState {
-        let filepath = e.path().to_str().unwrap();
+    /// Whether `filepath`'s extension makes it relevant to scan at all, and if so,
+    /// the path of a sibling `.pyi` stub to merge imports with (`None` for
+    /// extensions, like `.pxd`, that have no stub equivalent). Returns `None` when
+    /// the file should be skipped entirely: an unrecognized extension, or a `.pyx`
+    /// / `.pyi` superseded by a `.py`/`.pyx` counterpart (see below)
+    fn stub_for(filepath: &str) -> Option<Option<String>> {
         if filepath.ends_with(".py") {
             // normal case: plain python code
+            Some(Some(filepath.to_string() + "i"))
+        } else if filepath.ends_with(".pxd") {
+            // Cython declaration file: no .py equivalent can exist, always scan it
+            Some(None)
         } else if (filepath.ends_with(".pyx") || filepath.ends_with(".pyi"))
             && !fs::exists(&filepath[..filepath.len() - 1]).unwrap_or(true)
         {
@@ -545,20 +632,86 @@ impl ModuleGraph {
             // is not part of normal source tree, but that might still have
             // relevant dependency information
 
-            // give precedence to pyx over pyi if both are present
+            // give precedence to pyx over pyi if both are present: the .pyx pass
+            // below picks up the .pyi's imports itself, so the .pyi is skipped here
             if filepath.ends_with("i")
                 && fs::exists(&(filepath[..filepath.len() - 1].to_string() + "x")).unwrap_or(false)
             {
-                return WalkState::Continue;
+                return None;
+            }
+            if filepath.ends_with("x") {
+                Some(Some(filepath[..filepath.len() - 1].to_string() + "i"))
+            } else {
+                Some(None)
             }
-            info!("info: allowing {}", filepath);
         } else {
+            None
+        }
+    }
+
+    fn parse_one_file(
+        &self,
+        e: DirEntry,
+        include_typechecking: bool,
+        _tx: &mpsc::Sender<anyhow::Error>,
+    ) -> WalkState {
+        let filepath = e.path().to_str().unwrap();
+        if e.file_type().is_some_and(|t| t.is_dir()) {
+            if dir_looks_like_pep420_namespace(filepath) {
+                self.add_pep420_namespace(filepath);
+            }
             return WalkState::Continue;
         }
+        let stub_path = match Self::stub_for(filepath) {
+            Some(stub_path) => stub_path,
+            None => return WalkState::Continue,
+        };
+        if !filepath.ends_with(".py") {
+            info!("info: allowing {}", filepath);
+        }
+        self.parse_file_path_with_stub(filepath, stub_path, include_typechecking);
+        WalkState::Continue
+    }
+
+    /// Register a directory with no `__init__.py[ix]` of its own as a PEP 420 implicit
+    /// namespace package, so that `import pkg.subpkg` still resolves even when
+    /// `subpkg` has no `__init__.py`. The synthetic `<dir>/__init__.py` fs key doesn't
+    /// correspond to a real file, but it's exactly what `to_module_no_cache` probes
+    /// for, so later imports of this package resolve the same way any other would
+    fn add_pep420_namespace(&self, dirpath: &str) {
+        let Some((pkg, module)) = self.fs_to_py(dirpath) else {
+            return;
+        };
+        let synthetic_init = dirpath.to_string() + MAIN_SEPARATOR_STR + "__init__.py";
+        self.add(
+            &synthetic_init,
+            pkg,
+            &module,
+            Vec::new(),
+            NamespaceKind::Pep420Implicit,
+            None,
+        );
+    }
+
+    /// Re-parse a single file and record its direct imports via `add`, exactly as
+    /// during the parallel walk. Used by `update_files` to mend a single changed
+    /// file without re-walking the whole tree
+    fn parse_file_path(&self, filepath: &str, include_typechecking: bool) {
+        if let Some(stub_path) = Self::stub_for(filepath) {
+            self.parse_file_path_with_stub(filepath, stub_path, include_typechecking);
+        }
+    }
+
+    fn parse_file_path_with_stub(
+        &self,
+        filepath: &str,
+        stub_path: Option<String>,
+        include_typechecking: bool,
+    ) {
         debug!("parse: {}", filepath);
         let res = self.fs_to_py(filepath);
         if res.is_none() {
-            return WalkState::Continue;
+            return;
         }
         let (pkg, module) = res.unwrap();
 
@@ -568,25 +721,71 @@ impl ModuleGraph {
         let module = module[..suffix_idx].replace(MAIN_SEPARATOR, ".");
 
         match raw_get_all_imports(filepath, &module, true, include_typechecking) {
-            Ok((is_ns_pkg_init, imports)) => {
+            Ok((ns_kind, mut imports, dunder_all)) => {
+                // a `.pyi` stub and its `.py`/`.pyx` implementation frequently declare
+                // different imports (type-only vs runtime): union both sets rather than
+                // picking one, mirroring mypy's stub/source merge. Deduplication of any
+                // overlap happens for free via the `HashSet<ModuleRef>` built in `add`
+                // NB: the stub's own `__all__` is ignored: `__all__` only matters for the
+                // package's own `__init__.py`, and the implementation's value takes
+                // precedence when both exist
+                if let Some(stub) = stub_path.filter(|s| fs::exists(s).unwrap_or(false)) {
+                    match raw_get_all_imports(&stub, &module, true, include_typechecking) {
+                        Ok((_, stub_imports, _)) => imports.extend(stub_imports),
+                        Err(err) => warn!("{}: {}", stub, err),
+                    }
+                }
                 // rip out the __init__ bit now that we've dealt with any relative imports
                 let mut module: &str = &module;
                 if module.ends_with(".__init__") {
                     module = &module[..module.len() - 9];
                 }
-                self.add(filepath, pkg, module, imports, is_ns_pkg_init);
-                WalkState::Continue
+                self.add(filepath, pkg, module, imports, ns_kind, dunder_all);
             }
             Err(err) => {
                 warn!("{}: {}", filepath, err);
                 // which parse errors should abort the creation of the import graph?
                 //tx.send(err).unwrap();
-                WalkState::Continue
             }
         }
     }
 
-    fn module_or_parent(&self, m: &str) -> Option<ModuleRef> {
+    /// Resolve `m` to a known module, or failing that, its immediate parent package
+    /// (a dynamic dependency naming an attribute rather than a submodule should still
+    /// anchor on the enclosing package). `m` may carry a relative-import-style
+    /// leading-dot prefix (`.sibling`, `..pkg.mod`, or a bare `..`), in which case the
+    /// dots are resolved against `importer`'s own package path before lookup, walking
+    /// up one parent per dot the same way `imp.level` is handled for a real
+    /// `from . import x` statement. `from .. import *`'s empty-suffix edge case (a
+    /// bare run of dots with nothing after it) is handled by not appending a `.` when
+    /// there is no remaining suffix, rather than leaving a bogus trailing dot.
+    fn module_or_parent(&self, importer: ModuleRef, m: &str) -> Option<ModuleRef> {
+        let level = m.chars().take_while(|&c| c == '.').count();
+        let resolved;
+        let m = if level > 0 {
+            let importer_py = self.modules_refs.py_for_ref(importer);
+            // a package's leading dot refers to the package itself, as with the
+            // `pkg.__init__` module name the parser resolves `imp.level` against
+            let importer_fs = self.modules_refs.fs_for_ref(importer);
+            let file_name = importer_fs
+                .rsplit_once(MAIN_SEPARATOR)
+                .map_or(importer_fs.as_str(), |(_, n)| n);
+            let base = if file_name.starts_with("__init__.") {
+                importer_py.to_string() + ".__init__"
+            } else {
+                importer_py.to_string()
+            };
+            let (parent, _) = split_at_depth(&base, '.', level);
+            let suffix = &m[level..];
+            resolved = if suffix.is_empty() {
+                parent.to_string()
+            } else {
+                parent.to_string() + "." + suffix
+            };
+            resolved.as_str()
+        } else {
+            m
+        };
         if let Some(r) = self.modules_refs.ref_for_py(ustr(m), None) {
             Some(r)
         } else if let Some((parent, _)) = m.rsplit_once('.') {
@@ -597,53 +796,372 @@ impl ModuleGraph {
     }
 
     pub fn add_dynamic_dependencies(&self, dynamic_edges: HashMap<String, HashSet<String>>) {
+        // snapshot the module trie once up front rather than re-deriving it (or
+        // worse, re-scanning every known module) for each wildcard edge below
+        let trie = self.modules_refs.module_trie();
         for (m, deps) in dynamic_edges {
             if let Some(r) = self.modules_refs.first_matching_ref(ustr(&m)) {
                 debug!("dynamic dep: {} -> {} +{:?}", m, r, deps);
-                self.add_dynamic_dep(r, deps);
+                self.add_dynamic_dep(r, deps, &trie);
             } else {
                 warn!("dynamic dep: {} not found", m);
             }
         }
     }
 
-    fn add_dynamic_dep(&self, r: ModuleRef, deps: HashSet<String>) {
+    fn add_dynamic_dep(&self, r: ModuleRef, deps: HashSet<String>, trie: &ModuleTrie) {
         // NB: insert a new set of deps if none exits
         // this is necessary to allow attaching dynamic deps to external imports
         let mut cur_deps = self.global_ns.entry(r).or_default();
         deps.iter().for_each(|dep| {
             if dep.ends_with(".*") {
-                let dep_prefix = &dep[..dep.len() - 1];
+                let dep_prefix = &dep[..dep.len() - 2];
                 info!("dynamic wildcard: {}", dep_prefix);
-                // TODO: more efficient prefix search?
-                // probably overkill for now...
-                for mod_ref in 0..self.modules_refs.max_value() {
-                    let mod_py = self.modules_refs.py_for_ref(mod_ref);
-                    if let Some(suffix) = mod_py.strip_prefix(dep_prefix) {
-                        if !suffix.contains('.') {
-                            info!(" > wildcard match: {}", mod_py);
-                            cur_deps.insert(mod_ref);
-                        }
-                    }
+                for mod_ref in trie.direct_children(dep_prefix) {
+                    info!(" > wildcard match: {}", self.modules_refs.py_for_ref(mod_ref));
+                    cur_deps.insert(mod_ref);
                 }
-            } else if let Some(mod_ref) = self.module_or_parent(dep) {
+            } else if let Some(mod_ref) = self.module_or_parent(r, dep) {
                 cur_deps.insert(mod_ref);
             }
         })
     }
 
+    /// Detect import cycles among local modules: the strongly connected components of
+    /// the local-module subgraph of `global_ns`, found via Tarjan's algorithm. Uses an
+    /// explicit stack rather than native recursion, since real Python package graphs
+    /// can get deep enough to blow it. Only edges between `is_local` modules are
+    /// traversed, so external/stdlib nodes can't merge unrelated components together.
+    /// Every SCC of size > 1, plus any self-loop, is returned as one cycle (the import
+    /// paths of its member modules), mirroring Mercurial's import-checker
+    pub fn find_import_cycles(&self) -> Vec<Vec<String>> {
+        let is_local_ref = |r: ModuleRef| {
+            matches!(
+                self.is_local(self.modules_refs.py_for_ref(r).as_str()),
+                Some(true)
+            )
+        };
+        let local_neighbors = |v: ModuleRef| -> Vec<ModuleRef> {
+            self.global_ns
+                .get(&v)
+                .map(|e| {
+                    e.value()
+                        .iter()
+                        .copied()
+                        .filter(|&w| is_local_ref(w))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        // one frame per node on the (explicit) DFS call stack: its materialized,
+        // locally-filtered neighbor list, and how far we've gotten through it, so
+        // a "return" from a deeper frame can resume right where it left off
+        struct Frame {
+            node: ModuleRef,
+            neighbors: Vec<ModuleRef>,
+            next: usize,
+        }
+
+        let mut next_index = 0usize;
+        let mut index: HashMap<ModuleRef, usize> = HashMap::new();
+        let mut lowlink: HashMap<ModuleRef, usize> = HashMap::new();
+        let mut on_stack: HashSet<ModuleRef> = HashSet::new();
+        let mut tarjan_stack: Vec<ModuleRef> = Vec::new();
+        let mut sccs: Vec<Vec<ModuleRef>> = Vec::new();
+
+        let roots: Vec<ModuleRef> = self
+            .global_ns
+            .iter()
+            .map(|e| *e.key())
+            .filter(|&r| is_local_ref(r))
+            .collect();
+
+        for root in roots {
+            if index.contains_key(&root) {
+                continue;
+            }
+
+            let mut call_stack: Vec<Frame> = vec![Frame {
+                node: root,
+                neighbors: local_neighbors(root),
+                next: 0,
+            }];
+            index.insert(root, next_index);
+            lowlink.insert(root, next_index);
+            next_index += 1;
+            tarjan_stack.push(root);
+            on_stack.insert(root);
+
+            while !call_stack.is_empty() {
+                let top = call_stack.len() - 1;
+                let node = call_stack[top].node;
+                let next = call_stack[top].next;
+
+                if next < call_stack[top].neighbors.len() {
+                    let w = call_stack[top].neighbors[next];
+                    call_stack[top].next += 1;
+                    if !index.contains_key(&w) {
+                        index.insert(w, next_index);
+                        lowlink.insert(w, next_index);
+                        next_index += 1;
+                        tarjan_stack.push(w);
+                        on_stack.insert(w);
+                        call_stack.push(Frame {
+                            node: w,
+                            neighbors: local_neighbors(w),
+                            next: 0,
+                        });
+                    } else if on_stack.contains(&w) {
+                        let lv = lowlink[&node].min(index[&w]);
+                        lowlink.insert(node, lv);
+                    }
+                } else {
+                    call_stack.pop();
+                    if lowlink[&node] == index[&node] {
+                        let mut scc = Vec::new();
+                        loop {
+                            let w = tarjan_stack.pop().unwrap();
+                            on_stack.remove(&w);
+                            scc.push(w);
+                            if w == node {
+                                break;
+                            }
+                        }
+                        sccs.push(scc);
+                    }
+                    if let Some(parent) = call_stack.last() {
+                        let lp = lowlink[&parent.node].min(lowlink[&node]);
+                        lowlink.insert(parent.node, lp);
+                    }
+                }
+            }
+        }
+
+        sccs.into_iter()
+            .filter(|scc| scc.len() > 1 || local_neighbors(scc[0]).contains(&scc[0]))
+            .map(|scc| {
+                scc.iter()
+                    .map(|&r| self.modules_refs.py_for_ref(r).to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
     pub fn finalize(self) -> TransitiveClosure {
-        let mut module_refs = self.modules_refs.take();
-        reify_deps(&self.global_ns, &mut module_refs);
+        reify_deps(&self.global_ns, &self.modules_refs);
+        let module_refs = self.modules_refs.take();
         let mut unresolved = HashMap::with_capacity(self.unresolved.len());
         for (k, v) in self.unresolved {
             unresolved.insert(k, v);
         }
-        TransitiveClosure::from(&self.global_ns, module_refs, unresolved)
+        let mut entry_points = HashMap::with_capacity(self.entry_points.len());
+        for (k, v) in self.entry_points {
+            entry_points.insert(k, v);
+        }
+        TransitiveClosure::from(&self.global_ns, module_refs, unresolved, entry_points)
+    }
+
+    /// Non-consuming counterpart to `finalize`, for callers that need to keep `self`
+    /// around afterward (e.g. to later call `update_files`). Snapshots
+    /// `modules_refs` instead of draining it, at the cost of a clone
+    pub fn snapshot(&self) -> TransitiveClosure {
+        reify_deps(&self.global_ns, &self.modules_refs);
+        let module_refs = self.modules_refs.snapshot();
+        let mut unresolved = HashMap::with_capacity(self.unresolved.len());
+        for e in self.unresolved.iter() {
+            unresolved.insert(*e.key(), e.value().clone());
+        }
+        let mut entry_points = HashMap::with_capacity(self.entry_points.len());
+        for e in self.entry_points.iter() {
+            entry_points.insert(*e.key(), *e.value());
+        }
+        TransitiveClosure::from(&self.global_ns, module_refs, unresolved, entry_points)
+    }
+
+    /// Point-in-time copy of the module-ref cache, for syncing a `TransitiveClosure`
+    /// built via `snapshot`/`update_files` back up after an incremental update
+    pub fn snapshot_module_refs(&self) -> ModuleRefCache {
+        self.modules_refs.snapshot()
+    }
+
+    // accessors below expose the config this graph was built with, so a caller
+    // (e.g. `TransitiveClosure::to_text_file`) can record it in a header alongside
+    // the closure itself, without this struct having to know anything about
+    // serialization formats
+
+    pub fn source_roots(&self) -> &HashMap<String, String> {
+        &self.source_roots
+    }
+
+    pub fn global_prefixes(&self) -> &HashSet<String> {
+        &self.global_prefixes
+    }
+
+    pub fn local_prefixes(&self) -> &HashSet<String> {
+        &self.local_prefixes
+    }
+
+    pub fn external_prefixes(&self) -> HashSet<String> {
+        let mut out = HashSet::new();
+        self.external_prefixes.all_paths_into('.', &mut out);
+        out
+    }
+
+    pub fn stdlib_modules(&self) -> HashSet<String> {
+        let mut out = HashSet::new();
+        self.stdlib_matcher.all_paths_into('.', &mut out);
+        out
+    }
+
+    /// Re-parse `changed` files (dropping `deleted` ones) instead of rebuilding the
+    /// whole graph, returning the resulting direct-edge delta so
+    /// `TransitiveClosure::apply_update` can mend the condensed closure
+    /// incrementally. Star-imports inside the re-parsed files are re-resolved
+    /// against the current module set; star-imports in other, unchanged files that
+    /// target a re-parsed package are not, so a change to that package's `__all__`
+    /// does not reach them
+    pub fn update_files(
+        &self,
+        changed: &[String],
+        deleted: &[String],
+        include_typechecking: bool,
+    ) -> GraphEdgeDiff {
+        let mut diff = GraphEdgeDiff::default();
+
+        for filepath in deleted {
+            if let Some(r) = self.modules_refs.ref_for_fs(ustr(filepath)) {
+                if let Some((_, old_deps)) = self.global_ns.remove(&r) {
+                    for d in old_deps {
+                        diff.removed.push((r, d));
+                    }
+                }
+                diff.removed_nodes.push(r);
+            }
+        }
+
+        for filepath in changed {
+            let old_deps = self
+                .modules_refs
+                .ref_for_fs(ustr(filepath))
+                .and_then(|r| self.global_ns.get(&r).map(|e| (r, e.value().clone())));
+            // drop the file's previously collected star-imports, so that only the
+            // ones still present after re-parsing are resolved again below
+            if let Some((r, _)) = &old_deps {
+                self.pending_stars.remove(r);
+            }
+
+            self.parse_file_path(filepath, include_typechecking);
+
+            let new_ref = self.modules_refs.ref_for_fs(ustr(filepath));
+            if let Some(r) = new_ref {
+                // a re-parsed file's own dep set was just replaced wholesale by
+                // `add`; restore the implicit "depends on every parent __init__.py"
+                // edges that `finalize`/`snapshot` would otherwise only add once,
+                // at the end of a full parse
+                reify_one(&self.global_ns, &self.modules_refs, r);
+                // likewise for its star-import edges, which `add` only stashes
+                if let Some(stars) = self.pending_stars.get(&r).map(|e| e.value().clone()) {
+                    let trie = self.modules_refs.module_trie();
+                    for (pkg, target) in stars {
+                        self.resolve_star(r, &pkg, &target, &trie);
+                    }
+                }
+            }
+            let new_deps = new_ref.and_then(|r| self.global_ns.get(&r).map(|e| e.value().clone()));
+
+            match (old_deps, new_ref.zip(new_deps)) {
+                (Some((r, old)), Some((_, new))) => {
+                    for &d in old.difference(&new) {
+                        diff.removed.push((r, d));
+                    }
+                    for &d in new.difference(&old) {
+                        diff.inserted.push((r, d));
+                    }
+                }
+                (None, Some((r, new))) => {
+                    for &d in &new {
+                        diff.inserted.push((r, d));
+                    }
+                }
+                (Some((r, old)), None) => {
+                    for &d in &old {
+                        diff.removed.push((r, d));
+                    }
+                    diff.removed_nodes.push(r);
+                }
+                (None, None) => {}
+            }
+        }
+
+        diff.unresolved.reserve(self.unresolved.len());
+        for e in self.unresolved.iter() {
+            diff.unresolved.insert(*e.key(), e.value().clone());
+        }
+
+        diff
+    }
+}
+
+/// Load a previously persisted `TransitiveClosure` if the docket sitting next to it
+/// still validates against the current tree, otherwise parse `source_roots` from
+/// scratch and persist both a fresh closure and a fresh docket for the next run.
+/// Pass `force = true` (e.g. for a `--force` CLI flag) to skip the validation check
+/// and always rebuild, overwriting `cache_path`/`docket_path` even if they don't
+/// exist yet.
+#[allow(clippy::too_many_arguments)]
+pub fn load_or_build(
+    source_roots: HashMap<String, String>,
+    global_prefixes: HashSet<String>,
+    local_prefixes: HashSet<String>,
+    external_prefixes: HashSet<String>,
+    stdlib_modules: HashSet<String>,
+    include_typechecking: bool,
+    cache_path: &str,
+    docket_path: &str,
+    force: bool,
+) -> Result<TransitiveClosure, anyhow::Error> {
+    if !force {
+        if let Some(tc) = try_load_cached(&source_roots, cache_path, docket_path) {
+            debug!("loaded module graph from {} (docket {} still valid)", cache_path, docket_path);
+            return Ok(tc);
+        }
     }
+
+    let module_graph = ModuleGraph::new(
+        source_roots.clone(),
+        global_prefixes,
+        local_prefixes,
+        external_prefixes,
+        stdlib_modules,
+    );
+    module_graph.parse_parallel(include_typechecking)?;
+    let tc = module_graph.finalize();
+
+    let files = tc.all_source_files();
+    let docket = Docket::build(&source_roots, files.iter().map(|f| f.as_str()));
+    if let Err(e) = tc.to_file(cache_path) {
+        warn!("failed to persist module graph cache to {}: {}", cache_path, e);
+    } else if let Err(e) = docket.to_file(docket_path) {
+        warn!("failed to persist module graph docket to {}: {}", docket_path, e);
+    }
+
+    Ok(tc)
 }
 
-fn reify_deps(g: &DashMap<ModuleRef, HashSet<ModuleRef>>, ref_cache: &mut ModuleRefCache) {
+fn try_load_cached(
+    source_roots: &HashMap<String, String>,
+    cache_path: &str,
+    docket_path: &str,
+) -> Option<TransitiveClosure> {
+    let docket = Docket::from_file(docket_path).ok()?;
+    if !docket.is_valid(source_roots) {
+        return None;
+    }
+    TransitiveClosure::from_file(cache_path).ok()
+}
+
+fn reify_deps(g: &DashMap<ModuleRef, HashSet<ModuleRef>>, ref_cache: &LockedModuleRefCache) {
     // because of the way python import machinery works, namely executing top-level
     // statements in a module body, and the existence of __init__.py:
     //
@@ -656,23 +1174,30 @@ fn reify_deps(g: &DashMap<ModuleRef, HashSet<ModuleRef>>, ref_cache: &mut Module
 
     let mut n: ModuleRef = 0;
     while n < ref_cache.max_value() {
-        let mut deps = g.entry(n).or_default();
-        // add dep on all parent __init__.py
-        let module = ref_cache.get(n);
-        let mut idx = module.py.rfind('.');
-        while idx.is_some() {
-            let parent = ustr(&module.py[..idx.unwrap()]);
-            let pref = ref_cache
-                .ref_for_py(parent, module.pkg)
-                // create ref for implicit namespace package
-                // we need this because Python will create implicit namespaces,
-                // and they will show up in the import tracker when validating
-                .unwrap_or_else(|| ref_cache.get_or_create(ustr(""), parent, module.pkg));
-            let pmod = ref_cache.get(pref);
-            assert_eq!(pmod.pkg, module.pkg);
-            deps.insert(pref);
-            idx = parent.rfind('.');
-        }
+        reify_one(g, ref_cache, n);
         n += 1;
     }
 }
+
+/// Add a dep on every parent `__init__.py` of module `n`, creating missing implicit
+/// namespace package refs along the way if needed. Factored out of `reify_deps` so
+/// `update_files` can reapply the same rule to just the modules it touched, rather
+/// than re-scanning the whole tree
+fn reify_one(g: &DashMap<ModuleRef, HashSet<ModuleRef>>, ref_cache: &LockedModuleRefCache, n: ModuleRef) {
+    let mut deps = g.entry(n).or_default();
+    let module = ref_cache.get(n);
+    let mut idx = module.py.rfind('.');
+    while idx.is_some() {
+        let parent = ustr(&module.py[..idx.unwrap()]);
+        let pref = ref_cache
+            .ref_for_py(parent, module.pkg)
+            // create ref for implicit namespace package
+            // we need this because Python will create implicit namespaces,
+            // and they will show up in the import tracker when validating
+            .unwrap_or_else(|| ref_cache.get_or_create(ustr(""), parent, module.pkg));
+        let pmod = ref_cache.get(pref);
+        assert_eq!(pmod.pkg, module.pkg);
+        deps.insert(pref);
+        idx = parent.rfind('.');
+    }
+}
@@ -1,7 +1,9 @@
 // SPDX-FileCopyrightText: © 2024 Hugues Bruant <hugues.bruant@gmail.com>
 
+pub mod docket;
 pub mod graph;
 pub mod matcher;
 pub mod moduleref;
 pub mod parser;
+pub mod stdlib;
 pub mod transitive_closure;
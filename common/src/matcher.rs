@@ -1,9 +1,19 @@
 // SPDX-FileCopyrightText: © 2024 Hugues Bruant <hugues.bruant@gmail.com>
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub struct MatcherNode {
     is_leaf: bool,
+    // true if `children` contains a `*` or `**` wildcard segment, so that
+    // `advance_frontier` can skip the `*`/`**` lookups entirely in the (common)
+    // all-literal subtrees
+    has_wildcard: bool,
+    // true if this node is itself the target of a `**` edge, i.e. it was reached by
+    // matching zero-or-more segments. Such a node implicitly self-loops, consuming
+    // one more arbitrary segment and staying put, which is what lets `**` span any
+    // number of segments even after its owning parent has dropped out of the
+    // frontier (see `advance_frontier`)
+    is_double_star_target: bool,
     children: HashMap<String, MatcherNode>,
 }
 
@@ -17,6 +27,8 @@ impl MatcherNode {
     pub fn new() -> MatcherNode {
         MatcherNode {
             is_leaf: false,
+            has_wildcard: false,
+            is_double_star_target: false,
             children: HashMap::new(),
         }
     }
@@ -35,49 +47,120 @@ impl MatcherNode {
             if !node.children.contains_key(p) {
                 node.children.insert(p.to_string(), MatcherNode::new());
             }
-            node = node.children.get_mut(p).unwrap()
+            if p == "*" || p == "**" {
+                node.has_wildcard = true;
+            }
+            node = node.children.get_mut(p).unwrap();
+            if p == "**" {
+                node.is_double_star_target = true;
+            }
         }
         node.is_leaf = true;
     }
 
+    fn key(&self) -> usize {
+        self as *const MatcherNode as usize
+    }
+
+    fn push_unique<'a>(
+        seen: &mut HashSet<usize>,
+        frontier: &mut Vec<&'a MatcherNode>,
+        n: &'a MatcherNode,
+    ) {
+        if seen.insert(n.key()) {
+            frontier.push(n);
+        }
+    }
+
+    /// Expand `frontier` with every `**` child reachable by consuming zero segments,
+    /// recursively (a pattern can chain more than one `**`, e.g. `a.**.b.**.c`).
+    /// Shared by `matches`/`strict_prefix`/`longest_prefix_len` so each can walk a
+    /// frontier of candidate nodes instead of a single pointer once a wildcard is in
+    /// play, per `has_wildcard`
+    fn epsilon_closure<'a>(frontier: &[&'a MatcherNode]) -> Vec<&'a MatcherNode> {
+        let mut seen: HashSet<usize> = frontier.iter().map(|n| n.key()).collect();
+        let mut result: Vec<&MatcherNode> = frontier.to_vec();
+        let mut i = 0;
+        while i < result.len() {
+            if let Some(m) = result[i].children.get("**") {
+                Self::push_unique(&mut seen, &mut result, m);
+            }
+            i += 1;
+        }
+        result
+    }
+
+    /// Advance every node in `frontier` by one input segment: follow a matching
+    /// literal child, follow a `*` child unconditionally, and follow a `**` child by
+    /// consuming this segment while staying on it (its zero-segment case is handled
+    /// separately by `epsilon_closure`). Nodes with no wildcard children skip the
+    /// `*`/`**` lookups entirely, keeping the common literal-only case cheap
+    fn advance_frontier<'a>(frontier: &[&'a MatcherNode], segment: &str) -> Vec<&'a MatcherNode> {
+        let mut seen: HashSet<usize> = HashSet::new();
+        let mut next: Vec<&MatcherNode> = Vec::new();
+        for &n in frontier {
+            if let Some(m) = n.children.get(segment) {
+                Self::push_unique(&mut seen, &mut next, m);
+            }
+            if n.is_double_star_target {
+                Self::push_unique(&mut seen, &mut next, n);
+            }
+            if !n.has_wildcard {
+                continue;
+            }
+            if let Some(m) = n.children.get("*") {
+                Self::push_unique(&mut seen, &mut next, m);
+            }
+            if let Some(m) = n.children.get("**") {
+                Self::push_unique(&mut seen, &mut next, m);
+            }
+        }
+        next
+    }
+
+    /// Whether `value` either exactly matches, or extends past, a stored pattern
+    /// (`*`/`**` wildcard segments included). A leaf reached before `value` is fully
+    /// consumed still counts as a match: e.g. a tree built from `"qux/a"` matches
+    /// `"qux/a/sub"` too
     pub fn matches(&self, value: &str, sep: char) -> bool {
-        let mut n = self;
+        let mut frontier = Self::epsilon_closure(&[self]);
         for c in value.split(sep) {
-            if n.is_leaf {
+            if frontier.iter().any(|n| n.is_leaf) {
                 return true;
             }
-            match n.children.get(c) {
-                None => return false,
-                Some(m) => n = m,
+            frontier = Self::advance_frontier(&frontier, c);
+            frontier = Self::epsilon_closure(&frontier);
+            if frontier.is_empty() {
+                return false;
             }
         }
-        n.is_leaf
+        frontier.iter().any(|n| n.is_leaf)
     }
 
     pub fn strict_prefix(&self, value: &str, sep: char) -> bool {
-        let mut n = self;
+        let mut frontier = Self::epsilon_closure(&[self]);
         for c in value.split(sep) {
-            match n.children.get(c) {
-                None => return false,
-                Some(m) => n = m,
+            frontier = Self::advance_frontier(&frontier, c);
+            frontier = Self::epsilon_closure(&frontier);
+            if frontier.is_empty() {
+                return false;
             }
         }
-        !n.is_leaf
+        frontier.iter().any(|n| !n.is_leaf)
     }
 
     pub fn longest_prefix_len(&self, value: &str, sep: char) -> usize {
-        let mut n = self;
+        let mut frontier = Self::epsilon_closure(&[self]);
         let mut prefix_len: usize = 0;
         let mut idx: usize = 0;
         for c in value.split(sep) {
-            match n.children.get(c) {
-                None => return prefix_len,
-                Some(m) => {
-                    n = m;
-                }
+            frontier = Self::advance_frontier(&frontier, c);
+            frontier = Self::epsilon_closure(&frontier);
+            if frontier.is_empty() {
+                return prefix_len;
             }
             idx += c.len() + 1;
-            if n.is_leaf {
+            if frontier.iter().any(|n| n.is_leaf) {
                 prefix_len = idx - 1
             }
         }
@@ -122,6 +205,35 @@ impl MatcherNode {
             child.all_suffixes_into(&cp, sep, res);
         }
     }
+
+    /// Every literal pattern originally inserted via `add`/`from` (`*`/`**` wildcard
+    /// segments included verbatim), so a trie built for matching can be serialized
+    /// back out as a flat pattern list
+    pub fn all_paths_into<S, T>(&self, sep: char, res: &mut T)
+    where
+        S: From<String>,
+        T: Extend<S>,
+    {
+        self.all_paths_from("", sep, res);
+    }
+
+    fn all_paths_from<S, T>(&self, prefix: &str, sep: char, res: &mut T)
+    where
+        S: From<String>,
+        T: Extend<S>,
+    {
+        for (name, child) in &self.children {
+            let mut cp = prefix.to_string();
+            if !prefix.is_empty() {
+                cp.push(sep);
+            }
+            cp.push_str(name);
+            if child.is_leaf {
+                res.extend(Some(S::from(cp.clone())));
+            }
+            child.all_paths_from(&cp, sep, res);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -306,4 +418,76 @@ mod tests {
         assert_eq!("", m.longest_prefix("fool.ed", '.'));
         assert_eq!("", m.longest_prefix("baz.bar", '.'));
     }
+
+    #[test]
+    fn matches_single_star() {
+        let m = MatcherNode::from(vec!["tests.*.integration"], '.');
+        assert_eq!(true, m.matches("tests.unit.integration", '.'));
+        assert_eq!(true, m.matches("tests.unit.integration.sub", '.'));
+        assert_eq!(false, m.matches("tests.integration", '.'));
+        assert_eq!(false, m.matches("tests.a.b.integration", '.'));
+    }
+
+    #[test]
+    fn matches_double_star() {
+        let m = MatcherNode::from(vec!["myproj.**.vendored"], '.');
+        assert_eq!(true, m.matches("myproj.vendored", '.'));
+        assert_eq!(true, m.matches("myproj.a.vendored", '.'));
+        assert_eq!(true, m.matches("myproj.a.b.vendored", '.'));
+        assert_eq!(true, m.matches("myproj.a.b.vendored.sub", '.'));
+        assert_eq!(false, m.matches("myproj.a.b", '.'));
+        assert_eq!(false, m.matches("other.vendored", '.'));
+    }
+
+    #[test]
+    fn matches_mixed_slash_sep() {
+        let m = MatcherNode::from(vec!["src/*/generated/**", "src/shared"], '/');
+        assert_eq!(true, m.matches("src/foo/generated", '/'));
+        assert_eq!(true, m.matches("src/foo/generated/x/y", '/'));
+        assert_eq!(false, m.matches("src/foo/handwritten", '/'));
+        assert_eq!(true, m.matches("src/shared", '/'));
+        assert_eq!(true, m.matches("src/shared/sub", '/'));
+    }
+
+    #[test]
+    fn strict_prefix_wildcard() {
+        let m = MatcherNode::from(vec!["tests.*.integration", "foo.**.vendored"], '.');
+        assert_eq!(true, m.strict_prefix("tests", '.'));
+        assert_eq!(true, m.strict_prefix("tests.unit", '.'));
+        assert_eq!(false, m.strict_prefix("tests.unit.integration", '.'));
+        assert_eq!(false, m.strict_prefix("tests.unit.integration.sub", '.'));
+        assert_eq!(false, m.strict_prefix("tests.a.b", '.'));
+
+        assert_eq!(true, m.strict_prefix("foo", '.'));
+        assert_eq!(true, m.strict_prefix("foo.bar", '.'));
+        // `**` can also swallow "vendored" itself and keep going (e.g.
+        // "foo.vendored.vendored" is a valid longer match), so these remain
+        // strict prefixes even though they're also exact matches in their own right
+        assert_eq!(true, m.strict_prefix("foo.vendored", '.'));
+        assert_eq!(true, m.strict_prefix("foo.bar.vendored", '.'));
+    }
+
+    #[test]
+    fn longest_prefix_wildcard() {
+        let m = MatcherNode::from(vec!["tests.*.integration", "foo.**.vendored"], '.');
+        assert_eq!("", m.longest_prefix("tests", '.'));
+        assert_eq!("", m.longest_prefix("tests.unit", '.'));
+        assert_eq!(
+            "tests.unit.integration",
+            m.longest_prefix("tests.unit.integration", '.')
+        );
+        assert_eq!(
+            "tests.unit.integration",
+            m.longest_prefix("tests.unit.integration.sub", '.')
+        );
+        assert_eq!("", m.longest_prefix("tests.a.b", '.'));
+
+        assert_eq!("foo.vendored", m.longest_prefix("foo.vendored", '.'));
+        assert_eq!("foo.bar.vendored", m.longest_prefix("foo.bar.vendored", '.'));
+        assert_eq!(
+            "foo.bar.vendored",
+            m.longest_prefix("foo.bar.vendored.sub", '.')
+        );
+        assert_eq!("", m.longest_prefix("foo.bar", '.'));
+    }
 }
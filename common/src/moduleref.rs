@@ -2,7 +2,7 @@
 
 use speedy::private::{read_length_u64_varint, write_length_u64_varint};
 use speedy::{Context, Readable, Reader, Writable, Writer};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::MAIN_SEPARATOR;
 use std::sync::RwLock;
 use ustr::{ustr, Ustr};
@@ -22,9 +22,36 @@ impl ModuleRefVal {
     }
 }
 
+// parallel columns rather than a single Vec<ModuleRefVal>, so that traversals which
+// only touch one attribute across many refs (e.g. dependency-graph walks over `py`)
+// don't drag the other two fields through cache for no reason
+#[derive(Debug, Clone, Default)]
+struct ModuleRefColumns {
+    fs: Vec<Ustr>,
+    py: Vec<Ustr>,
+    pkg: Vec<Option<Ustr>>,
+}
+
+impl ModuleRefColumns {
+    fn len(&self) -> usize {
+        self.py.len()
+    }
+
+    fn push(&mut self, v: ModuleRefVal) {
+        self.fs.push(v.fs);
+        self.py.push(v.py);
+        self.pkg.push(v.pkg);
+    }
+
+    fn get(&self, r: ModuleRef) -> ModuleRefVal {
+        let i = r as usize;
+        ModuleRefVal::new(self.fs[i], self.py[i], self.pkg[i])
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ModuleRefCache {
-    values: Vec<ModuleRefVal>,
+    values: ModuleRefColumns,
     fs_to_ref: HashMap<Ustr, ModuleRef>,
     py_to_ref_global: HashMap<Ustr, ModuleRef>,
     py_to_ref_local: HashMap<Ustr, HashMap<Ustr, ModuleRef>>,
@@ -44,7 +71,7 @@ impl LockedModuleRefCache {
     pub fn new() -> LockedModuleRefCache {
         LockedModuleRefCache {
             inner: RwLock::new(ModuleRefCache {
-                values: Vec::new(),
+                values: ModuleRefColumns::default(),
                 fs_to_ref: HashMap::new(),
                 py_to_ref_global: HashMap::new(),
                 py_to_ref_local: HashMap::new(),
@@ -89,6 +116,36 @@ impl LockedModuleRefCache {
     pub fn get_or_create(&self, fs: Ustr, py: Ustr, pkg: Option<Ustr>) -> ModuleRef {
         self.inner.write().unwrap().get_or_create(fs, py, pkg)
     }
+
+    pub fn validate(&self) {
+        self.inner.read().unwrap().validate()
+    }
+
+    /// Clone out a point-in-time copy of the underlying cache, for callers (e.g. an
+    /// incremental `TransitiveClosure` update) that need a snapshot to keep working
+    /// with while this cache itself keeps accumulating new refs concurrently
+    pub fn snapshot(&self) -> ModuleRefCache {
+        self.inner.read().unwrap().clone()
+    }
+
+    /// Fold a worker-local `ModuleRefCache` into this one, reapplying the same
+    /// fs/py/pkg consistency rules as `get_or_create`. Returns a remap table from
+    /// `other`'s `ModuleRef` values to refs in this cache.
+    ///
+    /// NB: `graph::ModuleGraph::parse_parallel` doesn't build per-worker caches and
+    /// call this yet, so it isn't actually off the hot path there today: every
+    /// worker still resolves imports against one `LockedModuleRefCache` behind a
+    /// single `RwLock`. Wiring it up would also require making `global_ns`/
+    /// `unresolved`/`dunder_all`/`pending_stars`/`entry_points` worker-local and
+    /// remapping their `ModuleRef` keys/values through the table this returns, since
+    /// they're only meaningful within whatever ref-space produced them.
+    pub fn merge(&self, other: ModuleRefCache) -> Vec<ModuleRef> {
+        self.inner.write().unwrap().merge_from(&other)
+    }
+
+    pub fn module_trie(&self) -> ModuleTrie {
+        self.inner.read().unwrap().module_trie()
+    }
 }
 
 impl ModuleRefCache {
@@ -96,7 +153,8 @@ impl ModuleRefCache {
         let mut fs_to_ref = HashMap::new();
         let mut py_to_ref_global = HashMap::new();
         let mut py_to_ref_local: HashMap<Ustr, HashMap<Ustr, ModuleRef>> = HashMap::new();
-        for (i, v) in values.iter().enumerate() {
+        let mut columns = ModuleRefColumns::default();
+        for (i, v) in values.into_iter().enumerate() {
             if !v.fs.is_empty() {
                 fs_to_ref.insert(v.fs, i as ModuleRef);
             }
@@ -107,9 +165,10 @@ impl ModuleRefCache {
                     .or_default()
                     .insert(v.py, i as ModuleRef),
             };
+            columns.push(v);
         }
         Self {
-            values,
+            values: columns,
             fs_to_ref,
             py_to_ref_global,
             py_to_ref_local,
@@ -121,17 +180,17 @@ impl ModuleRefCache {
     }
 
     pub fn get(&self, r: ModuleRef) -> ModuleRefVal {
-        self.values[r as usize]
+        self.values.get(r)
     }
 
     pub fn py_for_ref(&self, r: ModuleRef) -> Ustr {
-        self.values[r as usize].py
+        self.values.py[r as usize]
     }
     pub fn fs_for_ref(&self, r: ModuleRef) -> Ustr {
-        self.values[r as usize].fs
+        self.values.fs[r as usize]
     }
     pub fn pkg_for_ref(&self, r: ModuleRef) -> Option<Ustr> {
-        self.values[r as usize].pkg
+        self.values.pkg[r as usize]
     }
 
     pub fn first_matching_ref(&self, m: Ustr) -> Option<ModuleRef> {
@@ -170,11 +229,11 @@ impl ModuleRefCache {
                 return r;
             }
         } else if let Some(&r) = self.fs_to_ref.get(&fs) {
-            assert_eq!(self.values[r as usize].pkg, pkg);
-            assert_eq!(self.values[r as usize].py, py);
+            assert_eq!(self.values.pkg[r as usize], pkg);
+            assert_eq!(self.values.py[r as usize], py);
             return r;
         } else if let Some(r) = self.ref_for_py(py, pkg) {
-            let rfs = self.values[r as usize].fs;
+            let rfs = self.values.fs[r as usize];
             // we don't want hard mismatch here, but we allow soft mismatch for
             // weird cases where a namespace package has sibling modules
             assert!(
@@ -206,7 +265,7 @@ impl ModuleRefCache {
                     "{} {} {:?}",
                     py,
                     fs,
-                    self.values[*self.py_to_ref_global.get(&py).unwrap() as usize]
+                    self.values.get(*self.py_to_ref_global.get(&py).unwrap())
                 );
                 self.py_to_ref_global.insert(py, r)
             }
@@ -214,9 +273,23 @@ impl ModuleRefCache {
         r
     }
 
+    /// Fold `other` into `self`, reapplying the same fs/py/pkg consistency rules as
+    /// `get_or_create` one entry at a time. Returns a remap table translating `other`'s
+    /// `ModuleRef` values to refs in `self`, so that edges collected against a
+    /// worker-local cache could be rewritten to point into the merged one (see the
+    /// caveat on `LockedModuleRefCache::merge`: no caller does this yet).
+    fn merge_from(&mut self, other: &ModuleRefCache) -> Vec<ModuleRef> {
+        let mut remap = Vec::with_capacity(other.values.len());
+        for r in 0..other.values.len() {
+            let v = other.values.get(r as ModuleRef);
+            remap.push(self.get_or_create(v.fs, v.py, v.pkg));
+        }
+        remap
+    }
+
     pub fn validate(&self) {
         for r in 0..self.values.len() {
-            let rv = &self.values[r];
+            let rv = self.values.get(r as ModuleRef);
             if !rv.fs.is_empty() {
                 assert_eq!(self.ref_for_fs(rv.fs), Some(r as ModuleRef));
             }
@@ -229,6 +302,87 @@ impl ModuleRefCache {
             );
         }
     }
+
+    /// Every filesystem path backing a known module, i.e. every file that
+    /// contributed to this cache (namespace packages and other fs-less refs are
+    /// skipped, since there is nothing on disk to fingerprint for them)
+    pub fn all_fs_paths(&self) -> Vec<Ustr> {
+        self.values.fs.iter().copied().filter(|fs| !fs.is_empty()).collect()
+    }
+
+    /// Build a `ModuleTrie` snapshot of every known `py` path, for callers that need
+    /// to answer several `pkg.*` wildcard queries without repeating an O(N) scan for
+    /// each of them
+    pub fn module_trie(&self) -> ModuleTrie {
+        let mut trie = ModuleTrie::new();
+        for r in 0..self.values.len() as ModuleRef {
+            trie.insert(self.py_for_ref(r), r);
+        }
+        trie
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    // every ModuleRef anchored exactly at this path segment; usually a single entry,
+    // but distinct packages can have local-scoped modules that share the same dotted
+    // `py` path, so this has to be a set rather than a single ref
+    modules: Vec<ModuleRef>,
+    children: HashMap<Ustr, usize>,
+}
+
+/// A per-segment radix trie over dotted module import paths (one node per path
+/// segment), used to turn a `pkg.*` wildcard into an O(depth + matches) lookup of
+/// `pkg`'s direct children instead of an O(N) scan over every known module.
+/// Modeled on Mercurial's NodeTree: a flat, index-addressed vector of nodes rather
+/// than a pointer-heavy tree of boxed children.
+#[derive(Debug, Default)]
+pub struct ModuleTrie {
+    nodes: Vec<TrieNode>,
+}
+
+impl ModuleTrie {
+    fn new() -> ModuleTrie {
+        ModuleTrie {
+            nodes: vec![TrieNode::default()], // root, at index 0
+        }
+    }
+
+    fn insert(&mut self, py: Ustr, r: ModuleRef) {
+        let mut node = 0usize;
+        for seg in py.split('.') {
+            let seg = ustr(seg);
+            node = match self.nodes[node].children.get(&seg) {
+                Some(&n) => n,
+                None => {
+                    let n = self.nodes.len();
+                    self.nodes.push(TrieNode::default());
+                    self.nodes[node].children.insert(seg, n);
+                    n
+                }
+            };
+        }
+        self.nodes[node].modules.push(r);
+    }
+
+    /// `ModuleRef`s of the direct children of the module at dotted path `prefix`,
+    /// i.e. the existing `pkg.*` wildcard expansion rule (one level deep)
+    pub fn direct_children(&self, prefix: &str) -> Vec<ModuleRef> {
+        let mut node = 0usize;
+        if !prefix.is_empty() {
+            for seg in prefix.split('.') {
+                match self.nodes[node].children.get(&ustr(seg)) {
+                    Some(&n) => node = n,
+                    None => return Vec::new(),
+                }
+            }
+        }
+        self.nodes[node]
+            .children
+            .values()
+            .flat_map(|&c| self.nodes[c].modules.iter().copied())
+            .collect()
+    }
 }
 
 impl<C> Writable<C> for ModuleRefVal
@@ -281,15 +435,82 @@ where
     }
 }
 
+// length of the longest common byte prefix of `a` and `b` that is still a valid
+// char boundary in `b`, so the suffix we keep can be sliced out safely
+fn shared_prefix_len(a: &str, b: &str) -> usize {
+    let max = a.len().min(b.len());
+    let mut n = 0;
+    while n < max && a.as_bytes()[n] == b.as_bytes()[n] {
+        n += 1;
+    }
+    while n > 0 && !b.is_char_boundary(n) {
+        n -= 1;
+    }
+    n
+}
+
+fn intern(s: Ustr, pool: &mut Vec<Ustr>, index: &mut HashMap<Ustr, u64>) -> u64 {
+    *index.entry(s).or_insert_with(|| {
+        let i = pool.len() as u64;
+        pool.push(s);
+        i
+    })
+}
+
 impl<C> Writable<C> for ModuleRefCache
 where
     C: Context,
 {
     fn write_to<T: ?Sized + Writer<C>>(&self, writer: &mut T) -> Result<(), C::Error> {
         let g = &self.values;
+
+        // `py` and `pkg` names overlap heavily (a pkg name is very often also some
+        // other module's py name), so they share a single dedup'd string table
+        let mut name_pool: Vec<Ustr> = Vec::new();
+        let mut name_index: HashMap<Ustr, u64> = HashMap::new();
+        let py_idx: Vec<u64> = g
+            .py
+            .iter()
+            .map(|&py| intern(py, &mut name_pool, &mut name_index))
+            .collect();
+        let pkg_idx: Vec<u64> = g
+            .pkg
+            .iter()
+            .map(|&pkg| intern(pkg.unwrap_or_default(), &mut name_pool, &mut name_index))
+            .collect();
+
+        // fs paths cluster by directory, so dedup, sort, and delta-encode consecutive
+        // entries against their shared prefix to squeeze out the redundancy
+        let mut fs_pool: Vec<Ustr> = g.fs.iter().copied().collect::<HashSet<_>>().into_iter().collect();
+        fs_pool.sort();
+        let fs_index: HashMap<Ustr, u64> = fs_pool
+            .iter()
+            .enumerate()
+            .map(|(i, &s)| (s, i as u64))
+            .collect();
+        let fs_idx: Vec<u64> = g.fs.iter().map(|fs| fs_index[fs]).collect();
+
+        write_length_u64_varint(name_pool.len(), writer)?;
+        for s in &name_pool {
+            write_ustr_to(*s, writer)?;
+        }
+
+        write_length_u64_varint(fs_pool.len(), writer)?;
+        let mut prev = "";
+        for s in &fs_pool {
+            let s = s.as_str();
+            let shared = shared_prefix_len(prev, s);
+            write_length_u64_varint(shared, writer)?;
+            write_length_u64_varint(s.len() - shared, writer)?;
+            writer.write_bytes(s[shared..].as_bytes())?;
+            prev = s;
+        }
+
         write_length_u64_varint(g.len(), writer)?;
-        for v in g.iter() {
-            writer.write_value(v)?;
+        for i in 0..g.len() {
+            write_length_u64_varint(fs_idx[i] as usize, writer)?;
+            write_length_u64_varint(py_idx[i] as usize, writer)?;
+            write_length_u64_varint(pkg_idx[i] as usize, writer)?;
         }
         Ok(())
     }
@@ -300,10 +521,45 @@ where
     C: Context,
 {
     fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
+        let mut buf: Vec<u8> = Vec::new();
+
+        let name_pool_len = read_length_u64_varint(reader)?;
+        let mut name_pool = Vec::with_capacity(name_pool_len);
+        for _ in 0..name_pool_len {
+            name_pool.push(read_ustr_with_buf(reader, &mut buf)?);
+        }
+
+        let fs_pool_len = read_length_u64_varint(reader)?;
+        let mut fs_pool: Vec<Ustr> = Vec::with_capacity(fs_pool_len);
+        let mut prev = String::new();
+        for _ in 0..fs_pool_len {
+            let shared = read_length_u64_varint(reader)?;
+            let suffix_len = read_length_u64_varint(reader)?;
+            buf.resize(suffix_len, 0);
+            reader.read_bytes(buf.as_mut_slice())?;
+            let mut s = String::with_capacity(shared + suffix_len);
+            s.push_str(&prev[..shared]);
+            s.push_str(std::str::from_utf8(buf.as_slice()).map_err(|e| {
+                speedy::Error::custom(format!("{:?} {:?}", e, buf))
+            })?);
+            fs_pool.push(ustr(&s));
+            prev = s;
+        }
+
         let sz = read_length_u64_varint(reader)?;
         let mut values = Vec::with_capacity(sz);
         for _ in 0..sz {
-            values.push(reader.read_value::<ModuleRefVal>()?);
+            let fs_i = read_length_u64_varint(reader)?;
+            let py_i = read_length_u64_varint(reader)?;
+            let pkg_i = read_length_u64_varint(reader)?;
+            let fs = fs_pool[fs_i];
+            let py = name_pool[py_i];
+            let pkg = name_pool[pkg_i];
+            let pkg = match pkg.len() {
+                0 => None,
+                _ => Some(pkg),
+            };
+            values.push(ModuleRefVal { fs, py, pkg });
         }
         Ok(ModuleRefCache::from_values(values))
     }
@@ -535,4 +791,61 @@ mod tests {
         mrc.get_or_create(ustr("foo.py"), ustr("foo"), Some(ustr("foo")));
         mrc.get_or_create(ustr("foo.py"), ustr("bar"), Some(ustr("foo")));
     }
+
+    #[test]
+    fn merge_disjoint() {
+        let master = LockedModuleRefCache::default();
+        let mr0 = master.get_or_create(ustr("a/foo.py"), ustr("foo"), Some(ustr("a")));
+
+        let mut worker = ModuleRefCache::from_values(Vec::new());
+        let wr0 = worker.get_or_create(ustr("b/foo.py"), ustr("foo"), Some(ustr("b")));
+        let wr1 = worker.get_or_create(ustr("b/bar.py"), ustr("bar"), Some(ustr("b")));
+
+        let remap = master.merge(worker);
+        assert_eq!(2, remap.len());
+
+        let mr1 = remap[wr0 as usize];
+        let mr2 = remap[wr1 as usize];
+        assert_ne!(mr0, mr1);
+        assert_ne!(mr0, mr2);
+        assert_ne!(mr1, mr2);
+
+        assert_eq!("b/foo.py", master.fs_for_ref(mr1));
+        assert_eq!("foo", master.py_for_ref(mr1));
+        assert_eq!(Some(ustr("b")), master.pkg_for_ref(mr1));
+
+        assert_eq!("b/bar.py", master.fs_for_ref(mr2));
+        assert_eq!("bar", master.py_for_ref(mr2));
+        assert_eq!(Some(ustr("b")), master.pkg_for_ref(mr2));
+    }
+
+    #[test]
+    fn merge_overlapping() {
+        let master = LockedModuleRefCache::default();
+        let mr0 = master.get_or_create(ustr("foo.py"), ustr("foo"), None);
+
+        let mut worker = ModuleRefCache::from_values(Vec::new());
+        let wr0 = worker.get_or_create(ustr("foo.py"), ustr("foo"), None);
+        let wr1 = worker.get_or_create(ustr("bar.py"), ustr("bar"), None);
+
+        let remap = master.merge(worker);
+
+        // the entry that already existed in the master cache is folded in, not
+        // duplicated, and new entries are appended after it
+        assert_eq!(mr0, remap[wr0 as usize]);
+        assert_ne!(mr0, remap[wr1 as usize]);
+        assert_eq!("bar.py", master.fs_for_ref(remap[wr1 as usize]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn merge_disallow_mismatch() {
+        let master = LockedModuleRefCache::default();
+        master.get_or_create(ustr("foo.py"), ustr("foo"), None);
+
+        let mut worker = ModuleRefCache::from_values(Vec::new());
+        worker.get_or_create(ustr("foo.py"), ustr("bar"), None);
+
+        master.merge(worker);
+    }
 }
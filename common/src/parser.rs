@@ -3,11 +3,54 @@
 use anyhow::Context;
 use regex::Regex;
 use ruff_python_ast::visitor::source_order::{walk_expr, walk_stmt, SourceOrderVisitor};
-use ruff_python_ast::{Expr, ExprCall, Stmt};
+use ruff_python_ast::{BoolOp, Expr, ExprCall, Operator, Stmt, UnaryOp};
+
+/// Whether a dependency came from a plain `import a.b.c` (or an equivalent dynamic
+/// `__import__`/`import_module` call), where the *entire* dotted path must itself be an
+/// importable module/package, or from the `name` half of `from a.b import name`, where
+/// `name` might just be an attribute of `a.b` rather than a submodule. The two need
+/// different resolution rules: only the latter can fall back to stripping its last
+/// component and resolving the parent package instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportKind {
+    Plain,
+    From,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportRecord {
+    pub name: String,
+    // true for imports collected from the body of a `try: ... except ImportError:` (or
+    // `ModuleNotFoundError`) block, which are semantically optional and shouldn't be
+    // treated as hard dependencies
+    pub optional: bool,
+    pub kind: ImportKind,
+}
+
+/// Compatibility helper for callers that only care about the flat list of import names,
+/// regardless of whether they are optional
+pub fn flatten_import_records<'a, I: IntoIterator<Item = &'a ImportRecord>>(
+    records: I,
+) -> Vec<String> {
+    records.into_iter().map(|r| r.name.clone()).collect()
+}
+
+fn expr_is_import_error_type(expr: &Expr) -> bool {
+    if let Some(n) = expr.as_name_expr() {
+        n.id.as_str() == "ImportError" || n.id.as_str() == "ModuleNotFoundError"
+    } else if let Some(a) = expr.as_attribute_expr() {
+        a.attr.as_str() == "ImportError" || a.attr.as_str() == "ModuleNotFoundError"
+    } else if let Some(t) = expr.as_tuple_expr() {
+        t.elts.iter().any(expr_is_import_error_type)
+    } else {
+        false
+    }
+}
 use ruff_python_parser::{parse_module, ParseError};
-use ruff_text_size::Ranged;
+use std::collections::HashSet;
 use std::fmt::Display;
 use std::fs::read_to_string;
+use std::path::MAIN_SEPARATOR;
 use std::sync::LazyLock;
 use std::{fs, io};
 
@@ -52,6 +95,69 @@ fn _string_lit_arg(call: &ExprCall) -> Option<String> {
         .map(|lit| lit.value.to_string())
 }
 
+// opportunistic static evaluation of `__all__`, good enough to cover the vast majority
+// of real-world usage without attempting to be a general-purpose constant folder
+
+fn string_sequence_literal(expr: &Expr) -> Option<Vec<String>> {
+    let elts = if let Some(l) = expr.as_list_expr() {
+        &l.elts
+    } else if let Some(t) = expr.as_tuple_expr() {
+        &t.elts
+    } else if let Some(s) = expr.as_set_expr() {
+        &s.elts
+    } else {
+        return None;
+    };
+    elts.iter()
+        .map(|e| e.as_string_literal_expr().map(|lit| lit.value.to_string()))
+        .collect()
+}
+
+fn eval_all_value(expr: &Expr) -> Option<Vec<String>> {
+    if let Some(names) = string_sequence_literal(expr) {
+        return Some(names);
+    }
+    // `__all__ = [...] + [...]` (or similar list/tuple concatenation)
+    let bin = expr.as_bin_op_expr()?;
+    if bin.op != Operator::Add {
+        return None;
+    }
+    let mut names = eval_all_value(&bin.left)?;
+    names.extend(eval_all_value(&bin.right)?);
+    Some(names)
+}
+
+fn is_dunder_all(expr: &Expr) -> bool {
+    expr.as_name_expr().is_some_and(|n| n.id.as_str() == "__all__")
+}
+
+fn is_dunder_all_extend_call(call: &ExprCall) -> bool {
+    call.func
+        .as_attribute_expr()
+        .is_some_and(|a| a.attr.as_str() == "extend" && is_dunder_all(&a.value))
+}
+
+/// Tracks the best-effort statically-known value of `__all__` as a module is walked.
+/// Starts out `Unknown` (no binding seen yet); becomes `Known` once a statically
+/// evaluable assignment/augmentation is seen, and sticks at `Dynamic` forever as soon
+/// as anything we can't evaluate touches `__all__` (a comprehension, a call other than
+/// `.extend(...)`, a name bound elsewhere, etc.)
+#[derive(Debug, Clone)]
+enum AllState {
+    Unknown,
+    Known(Vec<String>),
+    Dynamic,
+}
+
+impl AllState {
+    fn into_names(self) -> Option<Vec<String>> {
+        match self {
+            AllState::Known(names) => Some(names),
+            AllState::Unknown | AllState::Dynamic => None,
+        }
+    }
+}
+
 fn _match_import_fn(call: &ExprCall) -> bool {
     if let Some(n) = call.func.as_name_expr() {
         return n.id.as_str() == "__import__" || n.id.as_str() == "import_module";
@@ -68,36 +174,155 @@ fn _match_import_fn(call: &ExprCall) -> bool {
 }
 
 struct ImportExtractor<'a> {
-    source: &'a str,
     module: &'a str,
     deep: bool,
     include_typechecking: bool,
 
-    imports: Vec<String>,
+    // local names currently bound to typing.TYPE_CHECKING, e.g. via
+    // `from typing import TYPE_CHECKING` or `from typing import TYPE_CHECKING as TC`
+    typing_aliases: HashSet<String>,
+    // local names currently bound to the `typing`/`typing_extensions` module itself,
+    // e.g. via `import typing` or `import typing as t`, to recognize `t.TYPE_CHECKING`
+    typing_module_aliases: HashSet<String>,
+
+    // >0 while walking the body of a `try:` block whose handlers catch
+    // ImportError/ModuleNotFoundError, so nested imports get flagged as optional
+    optional_depth: usize,
+
+    imports: Vec<ImportRecord>,
+
+    // best-effort static value of `__all__`, used to scope `from pkg import *`
+    // expansion; see `AllState`
+    all_state: AllState,
 }
 
 impl<'a> ImportExtractor<'a> {
-    fn new(
-        source: &'a str,
-        module: &'a str,
-        deep: bool,
-        include_typechecking: bool,
-    ) -> ImportExtractor<'a> {
+    fn new(module: &'a str, deep: bool, include_typechecking: bool) -> ImportExtractor<'a> {
         ImportExtractor {
-            source,
             module,
             deep,
             include_typechecking,
+            typing_aliases: HashSet::new(),
+            typing_module_aliases: HashSet::new(),
+            optional_depth: 0,
             imports: Vec::new(),
+            all_state: AllState::Unknown,
+        }
+    }
+
+    fn push_import(&mut self, name: String, kind: ImportKind) {
+        let optional = self.optional_depth > 0;
+        self.imports.push(ImportRecord { name, optional, kind });
+    }
+
+    // only straight-line top-level-shaped assignments are handled; anything else
+    // touching `__all__` permanently gives up on static evaluation
+    fn record_all_binding(&mut self, stmt: &Stmt) {
+        if matches!(self.all_state, AllState::Dynamic) {
+            return;
+        }
+        if let Some(assign) = stmt.as_assign_stmt() {
+            if assign.targets.len() == 1 && is_dunder_all(&assign.targets[0]) {
+                self.all_state = match eval_all_value(&assign.value) {
+                    Some(names) => AllState::Known(names),
+                    None => AllState::Dynamic,
+                };
+            }
+        } else if let Some(aug) = stmt.as_aug_assign_stmt() {
+            if aug.op == Operator::Add && is_dunder_all(&aug.target) {
+                self.extend_all(eval_all_value(&aug.value));
+            }
+        } else if let Some(expr_stmt) = stmt.as_expr_stmt() {
+            if let Some(call) = expr_stmt.value.as_call_expr() {
+                if is_dunder_all_extend_call(call) {
+                    let arg = match call.arguments.args.as_slice() {
+                        [arg] => eval_all_value(arg),
+                        _ => None,
+                    };
+                    self.extend_all(arg);
+                }
+            }
+        }
+    }
+
+    fn extend_all(&mut self, extra: Option<Vec<String>>) {
+        self.all_state = match (std::mem::replace(&mut self.all_state, AllState::Dynamic), extra) {
+            (AllState::Known(mut names), Some(extra)) => {
+                names.extend(extra);
+                AllState::Known(names)
+            }
+            _ => AllState::Dynamic,
+        };
+    }
+
+    fn is_type_checking_expr(&self, expr: &Expr) -> bool {
+        if let Some(n) = expr.as_name_expr() {
+            self.typing_aliases.contains(n.id.as_str())
+        } else if let Some(a) = expr.as_attribute_expr() {
+            a.attr.as_str() == "TYPE_CHECKING"
+                && a.value
+                    .as_name_expr()
+                    .is_some_and(|n| self.typing_module_aliases.contains(n.id.as_str()))
+        } else {
+            false
+        }
+    }
+
+    // returns Some(true) if the `if` body is the type-checking-only region (the
+    // `TYPE_CHECKING` case), Some(false) if the `orelse` is (the `not TYPE_CHECKING` case),
+    // or None if `test` isn't a TYPE_CHECKING guard we recognize
+    fn type_checking_guard(&self, test: &Expr) -> Option<bool> {
+        if self.is_type_checking_expr(test) {
+            return Some(true);
+        }
+        if let Some(u) = test.as_unary_op_expr() {
+            if u.op == UnaryOp::Not && self.is_type_checking_expr(&u.operand) {
+                return Some(false);
+            }
+        } else if let Some(b) = test.as_bool_op_expr() {
+            // `if TYPE_CHECKING or FOO:` is treated the same as a plain `if TYPE_CHECKING:`
+            // guard: the body can run purely because of the type-checking branch, so we
+            // still want to recognize it as (at least partially) type-checking-only
+            if b.op == BoolOp::Or && b.values.iter().any(|v| self.is_type_checking_expr(v)) {
+                return Some(true);
+            }
+        }
+        None
+    }
+
+    fn record_typing_bindings(&mut self, stmt: &Stmt) {
+        if let Some(imp) = stmt.as_import_stmt() {
+            for n in &imp.names {
+                if n.name.as_str() == "typing" || n.name.as_str() == "typing_extensions" {
+                    let bound = n.asname.as_ref().map_or(n.name.as_str(), |a| a.as_str());
+                    self.typing_module_aliases.insert(bound.to_string());
+                }
+            }
+        } else if let Some(imp) = stmt.as_import_from_stmt() {
+            let is_typing_module = imp.level == 0
+                && imp
+                    .module
+                    .as_ref()
+                    .is_some_and(|m| m.as_str() == "typing" || m.as_str() == "typing_extensions");
+            if is_typing_module {
+                for n in &imp.names {
+                    if n.name.as_str() == "TYPE_CHECKING" {
+                        let bound = n.asname.as_ref().map_or(n.name.as_str(), |a| a.as_str());
+                        self.typing_aliases.insert(bound.to_string());
+                    }
+                }
+            }
         }
     }
 }
 
 impl<'b> SourceOrderVisitor<'b> for ImportExtractor<'_> {
     fn visit_stmt(&mut self, stmt: &'b Stmt) {
+        self.record_typing_bindings(stmt);
+        self.record_all_binding(stmt);
         if let Some(imp) = stmt.as_import_stmt() {
             for n in &imp.names {
-                self.imports.push(n.name.to_string());
+                self.push_import(n.name.to_string(), ImportKind::Plain);
             }
         } else if let Some(imp) = stmt.as_import_from_stmt() {
             let mut target = String::new();
@@ -111,24 +336,59 @@ impl<'b> SourceOrderVisitor<'b> for ImportExtractor<'_> {
                 }
                 target.push_str(imp.module.as_ref().unwrap().as_str());
             }
-            self.imports.push(target.clone());
+            // the package/module being imported from must itself fully resolve
+            self.push_import(target.clone(), ImportKind::Plain);
             for n in &imp.names {
-                self.imports.push(target.clone() + "." + n.name.as_str());
+                // `name` might be a submodule, or just an attribute of `target`, so it
+                // gets the more permissive From resolution rules
+                self.push_import(target.clone() + "." + n.name.as_str(), ImportKind::From);
             }
         } else if self.deep {
             if let Some(if_stmt) = stmt.as_if_stmt() {
-                // quick and dirty: skip if TYPE_CHECKING / if typing.TYPE_CHECKING
-                // TODO: for added robustness:
-                //  - keep track of imports from typing package
-                //  - extract identifier from if condition and compare to imported symbol
-                let range = if_stmt.test.range();
-                let cond = &self.source[range.start().to_usize()..range.end().to_usize()];
-                if (cond == "TYPE_CHECKING" || cond == "typing.TYPE_CHECKING")
-                    && !self.include_typechecking
-                {
-                    // skip walking under
-                    return;
+                if !self.include_typechecking {
+                    match self.type_checking_guard(&if_stmt.test) {
+                        Some(true) => {
+                            // the body only runs under TYPE_CHECKING: skip it, but still
+                            // walk the elif/else clauses, which run at runtime
+                            for clause in &if_stmt.elif_else_clauses {
+                                self.visit_elif_else_clause(clause);
+                            }
+                            return;
+                        }
+                        Some(false) => {
+                            // `if not TYPE_CHECKING:` - the body is the runtime branch,
+                            // the elif/else clauses are the type-checking-only region
+                            self.visit_body(&if_stmt.body);
+                            return;
+                        }
+                        None => {}
+                    }
+                }
+            } else if let Some(try_stmt) = stmt.as_try_stmt() {
+                // imports guarded by `try: ... except ImportError:` (or
+                // ModuleNotFoundError) are optional: the surrounding code already
+                // handles their absence, so they shouldn't be treated as hard deps
+                let optional = try_stmt.handlers.iter().any(|h| {
+                    h.as_except_handler().is_some_and(|eh| match &eh.type_ {
+                        None => true, // bare except catches everything
+                        Some(t) => expr_is_import_error_type(t),
+                    })
+                });
+                if optional {
+                    self.optional_depth += 1;
+                    self.visit_body(&try_stmt.body);
+                    self.optional_depth -= 1;
+                } else {
+                    self.visit_body(&try_stmt.body);
                 }
+                for handler in &try_stmt.handlers {
+                    if let Some(eh) = handler.as_except_handler() {
+                        self.visit_body(&eh.body);
+                    }
+                }
+                self.visit_body(&try_stmt.orelse);
+                self.visit_body(&try_stmt.finalbody);
+                return;
             }
             walk_stmt(self, stmt);
         }
@@ -143,7 +403,9 @@ impl<'b> SourceOrderVisitor<'b> for ImportExtractor<'_> {
                     // NB: we will still flag dynamic imports
                     // as a refinement, we might want to avoid flagging dynamic imports
                     // if they are all statically resolvable...
-                    self.imports.push(arg);
+                    // __import__/import_module take a full dotted module path, just like
+                    // a plain `import`, so the same strict resolution rules apply
+                    self.push_import(arg, ImportKind::Plain);
                 }
             }
         } else if let Some(name) = expr.as_name_expr() {
@@ -153,7 +415,7 @@ impl<'b> SourceOrderVisitor<'b> for ImportExtractor<'_> {
                 // could be:
                 //      builtins.__import__ (which does not require an import)
                 //      importlib.__import__
-                self.imports.push("__import__".to_string());
+                self.push_import("__import__".to_string(), ImportKind::Plain);
             }
         }
         walk_expr(self, expr);
@@ -171,11 +433,157 @@ pub fn raw_imports_from_module<'a>(
     module: &'a str,
     deep: bool,
     include_typechecking: bool,
-) -> Result<Vec<String>, ParseError> {
+) -> Result<(Vec<ImportRecord>, Option<Vec<String>>), ParseError> {
     let m = parse_module(source)?;
-    let mut extractor = ImportExtractor::new(source, module, deep, include_typechecking);
+    let mut extractor = ImportExtractor::new(module, deep, include_typechecking);
     extractor.visit_body(&m.syntax().body);
-    Ok(extractor.imports)
+    Ok((extractor.imports, extractor.all_state.into_names()))
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find('#') {
+        Some(idx) => &line[..idx],
+        None => line,
+    }
+}
+
+// tracks net paren/bracket/brace depth opened on a line, so multi-line statements that
+// rely on an unclosed `(`/`[`/`{` (rather than a trailing backslash) can be recognized
+fn paren_depth_delta(line: &str) -> i32 {
+    let mut delta = 0;
+    for c in line.chars() {
+        match c {
+            '(' | '[' | '{' => delta += 1,
+            ')' | ']' | '}' => delta -= 1,
+            _ => {}
+        }
+    }
+    delta
+}
+
+// joins backslash-continued lines, as well as lines with unbalanced open
+// parens/brackets/braces, into a single logical line, after stripping comments, so that
+// a wrapped `from foo import (\n  bar,\n  baz,\n)` still scans as one statement
+fn cython_logical_lines(source: &str) -> Vec<String> {
+    let mut logical = Vec::new();
+    let mut cur = String::new();
+    let mut depth: i32 = 0;
+    for raw_line in source.lines() {
+        let line = strip_comment(raw_line).trim_end();
+        let (line, backslash_continued) = match line.strip_suffix('\\') {
+            Some(stripped) => (stripped, true),
+            None => (line, false),
+        };
+        cur.push_str(line);
+        depth = (depth + paren_depth_delta(line)).max(0);
+        if backslash_continued || depth > 0 {
+            cur.push(' ');
+        } else {
+            logical.push(std::mem::take(&mut cur));
+        }
+    }
+    if !cur.is_empty() {
+        logical.push(cur);
+    }
+    logical
+}
+
+fn cython_relative_prefix(head: &str) -> (usize, &str) {
+    let level = head.chars().take_while(|&c| c == '.').count();
+    (level, head[level..].trim())
+}
+
+fn push_cython_from_target(imports: &mut Vec<ImportRecord>, module: &str, head: &str, names: &str) {
+    let (level, head) = cython_relative_prefix(head);
+    let mut target = String::new();
+    if level > 0 {
+        let (parent, _) = split_at_depth(module, '.', level);
+        target.push_str(parent);
+    }
+    if !head.is_empty() {
+        if !target.is_empty() {
+            target.push('.');
+        }
+        target.push_str(head);
+    }
+    imports.push(ImportRecord {
+        name: target.clone(),
+        optional: false,
+        kind: ImportKind::Plain,
+    });
+    for name in names.split(',') {
+        // drop "as alias" and surrounding parens left over from multi-line imports
+        let name = name.trim().trim_matches(|c| c == '(' || c == ')').trim();
+        let name = name.split_whitespace().next().unwrap_or("");
+        if name.is_empty() || name == "*" {
+            continue;
+        }
+        imports.push(ImportRecord {
+            name: target.clone() + "." + name,
+            optional: false,
+            kind: ImportKind::From,
+        });
+    }
+}
+
+// best-effort, line-oriented extraction of import/cimport statements from Cython
+// source (*.pyx / *.pxd), since ruff's parser only understands plain Python grammar
+// and cannot be used to build a proper AST for Cython's extended syntax. This tolerates
+// (by ignoring) cdef/cpdef blocks and other Cython-specific constructs, and understands
+// backslash line continuations well enough to follow multi-line import statements.
+pub fn raw_imports_from_cython(source: &str, module: &str) -> Vec<ImportRecord> {
+    let mut imports = Vec::new();
+    for line in cython_logical_lines(source) {
+        let trimmed = line.trim_start();
+        if let Some(rest) = trimmed.strip_prefix("from ") {
+            if let Some(idx) = rest.find(" cimport ") {
+                push_cython_from_target(
+                    &mut imports,
+                    module,
+                    rest[..idx].trim(),
+                    &rest[idx + " cimport ".len()..],
+                );
+            } else if let Some(idx) = rest.find(" import ") {
+                push_cython_from_target(
+                    &mut imports,
+                    module,
+                    rest[..idx].trim(),
+                    &rest[idx + " import ".len()..],
+                );
+            }
+        } else if let Some(rest) = trimmed
+            .strip_prefix("cimport ")
+            .or_else(|| trimmed.strip_prefix("import "))
+        {
+            for name in rest.split(',') {
+                let name = name.trim().split_whitespace().next().unwrap_or("");
+                if !name.is_empty() {
+                    imports.push(ImportRecord {
+                        name: name.to_string(),
+                        optional: false,
+                        kind: ImportKind::Plain,
+                    });
+                }
+            }
+        }
+    }
+    imports
+}
+
+/// Which, if any, of the namespace-package idioms a package's `__init__.py` uses, or
+/// whether the package has no `__init__.py` at all (PEP 420 implicit namespace package)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamespaceKind {
+    None,
+    Pkgutil,
+    PkgResources,
+    Pep420Implicit,
+}
+
+impl NamespaceKind {
+    pub fn is_namespace(self) -> bool {
+        self != NamespaceKind::None
+    }
 }
 
 pub fn content_looks_like_pkgutil_ns_init(source: &str) -> bool {
@@ -188,6 +596,51 @@ pub fn content_looks_like_pkgutil_ns_init(source: &str) -> bool {
     RE.is_match_at(source, 0)
 }
 
+pub fn content_looks_like_pkg_resources_ns_init(source: &str) -> bool {
+    static RE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(
+            r#"(?:pkg_resources|__import__ *\( *('pkg_resources'|"pkg_resources") *\))\.declare_namespace *\( *__name__ *\)"#
+        ).unwrap()
+    });
+
+    RE.is_match(source)
+}
+
+pub fn classify_namespace_init_content(source: &str) -> NamespaceKind {
+    if content_looks_like_pkgutil_ns_init(source) {
+        NamespaceKind::Pkgutil
+    } else if content_looks_like_pkg_resources_ns_init(source) {
+        NamespaceKind::PkgResources
+    } else {
+        NamespaceKind::None
+    }
+}
+
+/// A directory is a PEP 420 implicit namespace package when it has no `__init__.py[ix]`
+/// of its own but does contain Python submodules (files or subdirectories), i.e. it's
+/// meant to be importable rather than just incidental non-package clutter on disk
+pub fn dir_looks_like_pep420_namespace(dir: &str) -> bool {
+    if fs::exists(format!("{}{}__init__.py", dir, MAIN_SEPARATOR)).unwrap_or(false)
+        || fs::exists(format!("{}{}__init__.pyi", dir, MAIN_SEPARATOR)).unwrap_or(false)
+        || fs::exists(format!("{}{}__init__.pyx", dir, MAIN_SEPARATOR)).unwrap_or(false)
+    {
+        return false;
+    }
+    match fs::read_dir(dir) {
+        Err(_) => false,
+        Ok(entries) => entries.filter_map(Result::ok).any(|e| {
+            let name = e.file_name().to_string_lossy().into_owned();
+            match e.file_type() {
+                Ok(t) if t.is_dir() => true,
+                Ok(t) if t.is_file() => {
+                    name.ends_with(".py") || name.ends_with(".pyi") || name.ends_with(".pyx")
+                }
+                _ => false,
+            }
+        }),
+    }
+}
+
 pub fn file_looks_like_pkgutil_ns_init(filepath: &str) -> Result<bool, anyhow::Error> {
     Ok(filepath.ends_with("__init__.py")
         && fs::exists(filepath).unwrap_or(false)
@@ -201,17 +654,46 @@ pub fn raw_get_all_imports(
     module: &str,
     deep: bool,
     include_typechecking: bool,
-) -> Result<(bool, Vec<String>), anyhow::Error> {
+) -> Result<(NamespaceKind, Vec<ImportRecord>, Option<Vec<String>>), anyhow::Error> {
     let source =
         read_to_string(filepath).with_context(|| format!("Failed to read {}", filepath))?;
-    if filepath.ends_with(".pyx") {
-        // TODO: extend ruff parser to support parsing *.pyx files
-        // or do a best-effort string extraction...
-        return Ok((false, Vec::new()));
-    }
-    Ok((
-        filepath.ends_with("__init__.py") && content_looks_like_pkgutil_ns_init(&source),
-        raw_imports_from_module(&source, module, deep, include_typechecking)
-            .with_context(|| format!("failed to parse {}", filepath))?,
-    ))
+    if filepath.ends_with(".pyx") || filepath.ends_with(".pxd") {
+        // ruff's parser only understands plain Python grammar, so Cython sources get a
+        // best-effort line-oriented scan instead of a proper AST-based extraction, which
+        // in particular means no static `__all__` evaluation for star-import scoping
+        return Ok((
+            NamespaceKind::None,
+            raw_imports_from_cython(&source, module),
+            None,
+        ));
+    }
+    let ns_kind = if filepath.ends_with("__init__.py") {
+        classify_namespace_init_content(&source)
+    } else {
+        NamespaceKind::None
+    };
+    let (imports, dunder_all) = raw_imports_from_module(&source, module, deep, include_typechecking)
+        .with_context(|| format!("failed to parse {}", filepath))?;
+    Ok((ns_kind, imports, dunder_all))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cython_from_import_paren_continuation() {
+        let source = "from foo import (\n    bar,\n    baz,\n)\n";
+        let imports = raw_imports_from_cython(source, "pkg");
+        let names: Vec<&str> = imports.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["foo", "foo.bar", "foo.baz"]);
+    }
+
+    #[test]
+    fn cython_from_import_backslash_continuation() {
+        let source = "from foo import \\\n    bar, baz\n";
+        let imports = raw_imports_from_cython(source, "pkg");
+        let names: Vec<&str> = imports.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["foo", "foo.bar", "foo.baz"]);
+    }
 }
@@ -0,0 +1,92 @@
+// SPDX-FileCopyrightText: © 2024 Hugues Bruant <hugues.bruant@gmail.com>
+
+// Best-effort enumeration of Python standard-library top-level module names, so
+// that callers don't have to hand-maintain `external_prefixes` for every stdlib
+// package. Follows the same approach as Mercurial's import-checker
+// `list_stdlib_modules()`: walk the stdlib directory for top-level modules and
+// packages, and fold in the handful of names that are compiled directly into
+// the interpreter and therefore never show up as files on disk.
+
+use std::collections::HashSet;
+use std::fs;
+
+// mirrors CPython's `sys.builtin_module_names`: modules linked directly into
+// the interpreter, which a directory walk of the stdlib can never find
+const BUILTIN_MODULE_NAMES: &[&str] = &[
+    "_abc",
+    "_ast",
+    "_codecs",
+    "_collections",
+    "_functools",
+    "_imp",
+    "_io",
+    "_locale",
+    "_operator",
+    "_signal",
+    "_sre",
+    "_stat",
+    "_string",
+    "_symtable",
+    "_thread",
+    "_tracemalloc",
+    "_warnings",
+    "_weakref",
+    "atexit",
+    "builtins",
+    "errno",
+    "faulthandler",
+    "gc",
+    "itertools",
+    "marshal",
+    "posix",
+    "pwd",
+    "sys",
+    "time",
+    "xxsubtype",
+];
+
+/// Enumerate top-level stdlib module/package names by walking `stdlib_dir`
+/// (typically the `stdlib` entry of `sysconfig.get_paths()`), recognizing
+/// `.py` modules, packages (directories containing `__init__.py`), and
+/// compiled extension modules (`.so` / `.pyd` / `.dylib`). Always includes
+/// [`BUILTIN_MODULE_NAMES`], since those never appear as files regardless of
+/// `stdlib_dir`. Passing `None` (or an unreadable path) yields just the
+/// builtin names.
+pub fn list_stdlib_modules(stdlib_dir: Option<&str>) -> HashSet<String> {
+    let mut names: HashSet<String> = BUILTIN_MODULE_NAMES.iter().map(|s| s.to_string()).collect();
+
+    let Some(dir) = stdlib_dir else {
+        return names;
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return names;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if path.is_dir() {
+            // a plain directory only counts as a package if it is actually
+            // importable; stdlib also ships non-package dirs (e.g. test data)
+            if path.join("__init__.py").is_file() {
+                names.insert(name.to_string());
+            }
+        } else if let Some(stem) = name.strip_suffix(".py") {
+            if stem != "__init__" {
+                names.insert(stem.to_string());
+            }
+        } else if let Some(stem) = name
+            .strip_suffix(".so")
+            .or_else(|| name.strip_suffix(".pyd"))
+            .or_else(|| name.strip_suffix(".dylib"))
+        {
+            // compiled extensions sometimes carry a platform tag, e.g.
+            // `_socket.cpython-311-x86_64-linux-gnu.so`
+            names.insert(stem.split('.').next().unwrap_or(stem).to_string());
+        }
+    }
+
+    names
+}
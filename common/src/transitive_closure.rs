@@ -1,7 +1,9 @@
 // SPDX-FileCopyrightText: © 2024 Hugues Bruant <hugues.bruant@gmail.com>
 
+use crate::graph::GraphEdgeDiff;
 use crate::moduleref::{
-    read_ustr_with_buf, write_ustr_to, ModuleRef, ModuleRefCache, ModuleRefVal,
+    read_ustr_with_buf, write_ustr_to, LockedModuleRefCache, ModuleRef, ModuleRefCache,
+    ModuleRefVal,
 };
 use dashmap::DashMap;
 use hi_sparse_bitset::config::_128bit;
@@ -9,11 +11,12 @@ use hi_sparse_bitset::BitSet;
 use log::{debug, warn};
 use speedy::private::{read_length_u64_varint, write_length_u64_varint};
 use speedy::{Context, Error, LittleEndian, Readable, Reader, Writable, Writer};
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
 use std::io;
 use std::io::Write;
-use std::ops::{ControlFlow, Sub};
+use std::ops::Sub;
 use ustr::{ustr, Ustr, UstrSet};
 
 type CondensedRef = usize;
@@ -37,9 +40,31 @@ pub struct TransitiveClosure {
 
     //
     pub unresolved: HashMap<Ustr, HashSet<ModuleRef>>,
+
+    // `__main__.py` entry points, keyed by the import path of the package they belong
+    // to (empty string for a top-level script)
+    entry_points: HashMap<Ustr, ModuleRef>,
+
+    // raw direct-edge graph, kept around rather than discarded once the condensed
+    // closure is built, so `apply_update` can incrementally mend the closure for a
+    // handful of changed files instead of rebuilding it from scratch
+    direct_edges: HashMap<ModuleRef, HashSet<ModuleRef>>,
+
+    // modules whose cached transitive closure may be stale after an incremental
+    // update that couldn't be patched precisely (an edge/file deletion): `depends_on`
+    // falls back to a memoized DFS over `direct_edges` for these instead of trusting
+    // `successor`/`ancestor`
+    dirty: RefCell<HashSet<ModuleRef>>,
+    dirty_reachable: RefCell<HashMap<ModuleRef, HashSet<ModuleRef>>>,
 }
 
 impl TransitiveClosure {
+    /// Every source file that contributed to this closure, for building (or
+    /// validating) a `docket::Docket` snapshot of the tree it was built from
+    pub fn all_source_files(&self) -> Vec<Ustr> {
+        self.module_refs.all_fs_paths()
+    }
+
     pub fn unresolved(&self) -> HashMap<String, HashSet<String>> {
         HashMap::from_iter(self.unresolved.iter().map(|(k, v)| {
             (
@@ -58,6 +83,7 @@ impl TransitiveClosure {
         g: &DashMap<ModuleRef, HashSet<ModuleRef>>,
         refs: ModuleRefCache,
         unresolved: HashMap<Ustr, HashSet<ModuleRef>>,
+        entry_points: HashMap<Ustr, ModuleRef>,
     ) -> TransitiveClosure {
         let n = refs.max_value() as usize;
         let mut state = StackTC {
@@ -86,6 +112,11 @@ impl TransitiveClosure {
             }
         }
 
+        let mut direct_edges = HashMap::with_capacity(g.len());
+        for it in g {
+            direct_edges.insert(*it.key(), it.value().clone());
+        }
+
         TransitiveClosure {
             module_refs: refs,
             mod_to_condensed: state.comp,
@@ -93,6 +124,10 @@ impl TransitiveClosure {
             successor: state.succ,
             ancestor,
             unresolved,
+            entry_points,
+            direct_edges,
+            dirty: RefCell::new(HashSet::new()),
+            dirty_reachable: RefCell::new(HashMap::new()),
         }
     }
 
@@ -161,27 +196,244 @@ impl TransitiveClosure {
         Ok(())
     }
 
-    pub fn to_text_file(&self, filepath: &str) -> Result<(), io::Error> {
+    /// Unique string identity for `r`, stable across a text round-trip: `py` alone
+    /// isn't a safe key because `py_to_ref_local` lets the same import path exist
+    /// under multiple local packages, so a node with a `pkg` falls back to its (always
+    /// unique) `fs` path instead, mirroring the same trick `to_small_text_file` uses
+    fn node_key(&self, r: ModuleRef) -> String {
+        let rv = self.module_refs.get(r);
+        match rv.pkg {
+            None => rv.py.to_string(),
+            Some(_) => rv.fs.to_string(),
+        }
+    }
+
+    /// Deterministic, line-oriented, human-readable dump of this closure: a header
+    /// recording the source roots/prefix sets the graph was built with (for
+    /// diffability/documentation only — they aren't needed to reconstruct the
+    /// closure), followed by one sorted line per node giving its direct dependencies
+    /// and unresolved-import status. Unlike `to_small_text_file`, this is
+    /// round-trippable via `from_text_file`: git-diff-friendly
+    /// enough to commit a baseline graph in CI and catch unexpected dependency growth
+    pub fn to_text_file(
+        &self,
+        filepath: &str,
+        source_roots: &HashMap<String, String>,
+        global_prefixes: &HashSet<String>,
+        local_prefixes: &HashSet<String>,
+        external_prefixes: &HashSet<String>,
+        stdlib_modules: &HashSet<String>,
+    ) -> Result<(), io::Error> {
         let mut w = io::BufWriter::new(File::create(filepath)?);
 
-        for c in 0..self.condensed_to_mod.len() as CondensedRef {
-            let mut nodes = Vec::<String>::with_capacity(self.condensed_to_mod[c].len());
-            for &v in &self.condensed_to_mod[c] {
-                nodes.push(self.module_refs.py_for_ref(v).to_string());
+        writeln!(w, "# prunepytest transitive-closure text format v1")?;
+
+        writeln!(w, "[source_roots]")?;
+        let mut roots: Vec<(&String, &String)> = source_roots.iter().collect();
+        roots.sort();
+        for (fs_path, py_path) in roots {
+            writeln!(w, "{} => {}", fs_path, py_path)?;
+        }
+
+        for (section, vals) in [
+            ("global_prefixes", global_prefixes),
+            ("local_prefixes", local_prefixes),
+            ("external_prefixes", external_prefixes),
+            ("stdlib_modules", stdlib_modules),
+        ] {
+            writeln!(w, "[{}]", section)?;
+            let mut sorted: Vec<&String> = vals.iter().collect();
+            sorted.sort();
+            for v in sorted {
+                writeln!(w, "{}", v)?;
             }
-            nodes.sort();
-            let mut succ = Vec::<String>::new();
-            for cs in &self.successor[c] {
-                for &v in &self.condensed_to_mod[cs] {
-                    succ.push(self.module_refs.py_for_ref(v).to_string());
-                }
+        }
+
+        writeln!(w, "[entry_points]")?;
+        let mut entries: Vec<(String, String)> = self
+            .entry_points
+            .iter()
+            .map(|(&pkg, &r)| (pkg.to_string(), self.node_key(r)))
+            .collect();
+        entries.sort();
+        for (pkg, key) in entries {
+            writeln!(w, "{} => {}", pkg, key)?;
+        }
+
+        // invert `unresolved` (name -> referrers) into (referrer -> names), so each
+        // node's line can carry its own resolution status
+        let mut unresolved_by_ref: HashMap<ModuleRef, Vec<String>> = HashMap::new();
+        for (name, refs) in &self.unresolved {
+            for &r in refs {
+                unresolved_by_ref
+                    .entry(r)
+                    .or_default()
+                    .push(name.to_string());
             }
-            succ.sort();
-            w.write_fmt(format_args!("{} : {}", nodes.join(","), succ.join(",")))?;
         }
+
+        writeln!(w, "[nodes]")?;
+        let mut keys: Vec<(String, ModuleRef)> = (0..self.module_refs.max_value())
+            .map(|r| (self.node_key(r), r))
+            .collect();
+        keys.sort();
+        for (key, r) in keys {
+            let rv = self.module_refs.get(r);
+            let pkg = rv.pkg.map(|p| p.to_string()).unwrap_or_default();
+
+            let mut deps: Vec<String> = self
+                .direct_edges
+                .get(&r)
+                .into_iter()
+                .flatten()
+                .map(|&d| self.node_key(d))
+                .collect();
+            deps.sort();
+
+            let mut unresolved = unresolved_by_ref.remove(&r).unwrap_or_default();
+            unresolved.sort();
+
+            writeln!(
+                w,
+                "{} : fs={} py={} pkg={} deps={} unresolved={}",
+                key,
+                rv.fs,
+                rv.py,
+                pkg,
+                deps.join(","),
+                unresolved.join(",")
+            )?;
+        }
+
         Ok(())
     }
 
+    /// Parse a file written by `to_text_file` back into an equivalent closure. The
+    /// header is read only far enough to skip past it: the source roots/prefix sets
+    /// it records are documentation for a human reader, not inputs the closure itself
+    /// needs. Direct edges, unresolved imports and entry points are reconstructed
+    /// from `[nodes]`/`[entry_points]` and fed through `TransitiveClosure::from`, the
+    /// same condensation path a freshly-parsed graph goes through, so the result has
+    /// the same modules, direct edges and reachability as one produced by
+    /// `finalize`/`snapshot`. `ModuleRef` numbers are reassigned in sorted-key order,
+    /// so they generally differ from those of the graph that was written out
+    pub fn from_text_file(filepath: &str) -> Result<TransitiveClosure, io::Error> {
+        let content = fs::read_to_string(filepath)?;
+
+        let bad_line = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed transitive-closure text line: {}", line),
+            )
+        };
+
+        struct Node {
+            key: String,
+            deps: Vec<String>,
+            unresolved: Vec<String>,
+        }
+
+        let mut section = "";
+        let mut entry_point_lines: Vec<(String, String)> = Vec::new();
+        let mut nodes: Vec<Node> = Vec::new();
+        let locked = LockedModuleRefCache::new();
+
+        for line in content.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = match name {
+                    "source_roots" | "global_prefixes" | "local_prefixes"
+                    | "external_prefixes" | "stdlib_modules" | "entry_points" | "nodes" => name,
+                    _ => return Err(bad_line(line)),
+                };
+                continue;
+            }
+            match section {
+                "entry_points" => {
+                    let (pkg, key) = line.split_once(" => ").ok_or_else(|| bad_line(line))?;
+                    entry_point_lines.push((pkg.to_string(), key.to_string()));
+                }
+                "nodes" => {
+                    let (key, rest) = line.split_once(" : ").ok_or_else(|| bad_line(line))?;
+                    let (mut fs_val, mut py_val, mut pkg_val) = ("", "", "");
+                    let (mut deps_val, mut unresolved_val) = ("", "");
+                    for field in rest.split(' ') {
+                        let (k, v) = field.split_once('=').ok_or_else(|| bad_line(line))?;
+                        match k {
+                            "fs" => fs_val = v,
+                            "py" => py_val = v,
+                            "pkg" => pkg_val = v,
+                            "deps" => deps_val = v,
+                            "unresolved" => unresolved_val = v,
+                            _ => return Err(bad_line(line)),
+                        }
+                    }
+                    let pkg = if pkg_val.is_empty() {
+                        None
+                    } else {
+                        Some(ustr(pkg_val))
+                    };
+                    locked.get_or_create(ustr(fs_val), ustr(py_val), pkg);
+                    nodes.push(Node {
+                        key: key.to_string(),
+                        deps: deps_val
+                            .split(',')
+                            .filter(|s| !s.is_empty())
+                            .map(String::from)
+                            .collect(),
+                        unresolved: unresolved_val
+                            .split(',')
+                            .filter(|s| !s.is_empty())
+                            .map(String::from)
+                            .collect(),
+                    });
+                }
+                // source_roots / prefix-set sections: informational only, skip
+                _ => {}
+            }
+        }
+
+        let refs = locked.take();
+        let mut key_to_ref: HashMap<String, ModuleRef> = HashMap::with_capacity(nodes.len());
+        for r in 0..refs.max_value() {
+            let rv = refs.get(r);
+            let key = match rv.pkg {
+                None => rv.py.to_string(),
+                Some(_) => rv.fs.to_string(),
+            };
+            key_to_ref.insert(key, r);
+        }
+
+        let g: DashMap<ModuleRef, HashSet<ModuleRef>> = DashMap::with_capacity(nodes.len());
+        let mut unresolved: HashMap<Ustr, HashSet<ModuleRef>> = HashMap::new();
+        for node in &nodes {
+            let Some(&r) = key_to_ref.get(&node.key) else {
+                continue;
+            };
+            let deps: HashSet<ModuleRef> = node
+                .deps
+                .iter()
+                .filter_map(|d| key_to_ref.get(d).copied())
+                .collect();
+            g.insert(r, deps);
+            for name in &node.unresolved {
+                unresolved.entry(ustr(name)).or_default().insert(r);
+            }
+        }
+
+        let mut entry_points: HashMap<Ustr, ModuleRef> =
+            HashMap::with_capacity(entry_point_lines.len());
+        for (pkg, key) in entry_point_lines {
+            if let Some(&r) = key_to_ref.get(&key) {
+                entry_points.insert(ustr(&pkg), r);
+            }
+        }
+
+        Ok(TransitiveClosure::from(&g, refs, unresolved, entry_points))
+    }
+
     pub fn to_file(&self, filepath: &str) -> Result<(), Error> {
         let file = File::create(filepath).map_err(|e| Error::custom(e.to_string()))?;
         let stream = zstd::Encoder::new(file, 0)
@@ -216,14 +468,152 @@ impl TransitiveClosure {
             .map(|m| self.depends_on(m))?
     }
 
+    /// Transitive dependency set of the `__main__.py` entry-point belonging to
+    /// `pkg_import_path` (pass `""` for a top-level script), so the pruning machinery
+    /// can reason about `python -m pkg` as an executable target, not just an ordinary
+    /// importable module
+    pub fn entry_point_depends_on(&self, pkg_import_path: &str) -> Option<HashSet<Ustr>> {
+        let m = *self.entry_points.get(&ustr(pkg_import_path))?;
+        self.depends_on(m)
+    }
+
     pub fn depends_on(&self, m: ModuleRef) -> Option<HashSet<Ustr>> {
-        let mut deps = HashSet::new();
+        Some(
+            self.reachable_set(m)
+                .iter()
+                .map(|&v| self.module_refs.py_for_ref(v))
+                .collect(),
+        )
+    }
+
+    /// Set of modules reachable from `m` (`m` included only if it's part of a cycle
+    /// reachable from itself), as raw `ModuleRef`s rather than import-path strings.
+    /// Factored out of `depends_on` so `dependency_path` can restrict its BFS
+    /// frontier without paying the cost of resolving every node to a `Ustr` first
+    fn reachable_set(&self, m: ModuleRef) -> HashSet<ModuleRef> {
+        if self.dirty.borrow().contains(&m) {
+            return self
+                .dirty_reachable
+                .borrow_mut()
+                .entry(m)
+                .or_insert_with(|| self.recompute_reachable(m))
+                .clone();
+        }
+        let mut reachable = HashSet::new();
         for c in &self.successor[self.mod_to_condensed[m as usize]] {
-            for &v in &self.condensed_to_mod[c] {
-                deps.insert(self.module_refs.py_for_ref(v));
+            reachable.extend(self.condensed_to_mod[c].iter().copied());
+        }
+        reachable
+    }
+
+    /// Set of modules that can reach `m` (`m` included), the mirror image of
+    /// `reachable_set`. Used by `dependency_path` to restrict its BFS to the only
+    /// nodes that could possibly lie on a `from -> to` chain. Falls back to a
+    /// brute-force forward DFS over `direct_edges` from every source node, keeping
+    /// those that reach `m`, when `m` is dirty: rarer and
+    /// more expensive than the bitset lookup, but only hit for an endpoint recently
+    /// touched by an incremental update that couldn't be patched precisely
+    fn reaches_set(&self, m: ModuleRef) -> HashSet<ModuleRef> {
+        if self.dirty.borrow().contains(&m) {
+            let mut reaches: HashSet<ModuleRef> = HashSet::from([m]);
+            for &src in self.direct_edges.keys() {
+                if self.recompute_reachable(src).contains(&m) {
+                    reaches.insert(src);
+                }
+            }
+            return reaches;
+        }
+        let mut reaches = HashSet::from([m]);
+        for c in &self.ancestor[self.mod_to_condensed[m as usize]] {
+            reaches.extend(self.condensed_to_mod[c].iter().copied());
+        }
+        reaches
+    }
+
+    /// Ordered chain of import paths `from -> ... -> to` (inclusive of both ends),
+    /// or `None` if `to` isn't reachable from `from`, to turn an opaque "this test
+    /// is affected" verdict into something a developer can follow. Runs a BFS over
+    /// `direct_edges`, but restricts the frontier to nodes that are both
+    /// reachable-from `from` and can-reach `to` first (using the condensed
+    /// closure), so the search can't wander off into an unrelated part of a huge
+    /// graph; the first path found is therefore guaranteed shortest
+    pub fn dependency_path(&self, from_module: &str, to_module: &str) -> Option<Vec<String>> {
+        let from = self.module_refs.ref_for_py(ustr(from_module), None)?;
+        let to = self.module_refs.ref_for_py(ustr(to_module), None)?;
+        self.dependency_path_between(from, to)
+    }
+
+    fn dependency_path_between(&self, from: ModuleRef, to: ModuleRef) -> Option<Vec<String>> {
+        if from == to {
+            return Some(vec![self.module_refs.py_for_ref(from).to_string()]);
+        }
+        if !self.reachable_set(from).contains(&to) {
+            return None;
+        }
+        let allowed = self.reaches_set(to);
+
+        let mut parent: HashMap<ModuleRef, ModuleRef> = HashMap::new();
+        let mut visited: HashSet<ModuleRef> = HashSet::from([from]);
+        let mut queue: VecDeque<ModuleRef> = VecDeque::from([from]);
+        while let Some(v) = queue.pop_front() {
+            let Some(neighbors) = self.direct_edges.get(&v) else {
+                continue;
+            };
+            for &w in neighbors {
+                if !allowed.contains(&w) || !visited.insert(w) {
+                    continue;
+                }
+                parent.insert(w, v);
+                if w == to {
+                    let mut path = vec![to];
+                    let mut cur = to;
+                    while cur != from {
+                        cur = parent[&cur];
+                        path.push(cur);
+                    }
+                    path.reverse();
+                    return Some(
+                        path.iter()
+                            .map(|&r| self.module_refs.py_for_ref(r).to_string())
+                            .collect(),
+                    );
+                }
+                queue.push_back(w);
+            }
+        }
+        None
+    }
+
+    /// For each test file transitively affected by `files`, one representative
+    /// import chain linking it to the first of `files` (in the order given) that it
+    /// depends on, listed from that changed file out to the test, so CI logs can
+    /// justify every selected test rather than just listing them
+    pub fn explain_affected_by_files<T: AsRef<str>, L: IntoIterator<Item = T>>(
+        &self,
+        files: L,
+    ) -> HashMap<Ustr, Vec<String>> {
+        let changed: Vec<Ustr> = files.into_iter().map(|f| ustr(f.as_ref())).collect();
+        let affected = self.affected_by_files(changed.iter().map(|u| u.as_str()));
+
+        let mut explained = HashMap::with_capacity(affected.len());
+        for test_file in affected {
+            let Some(to) = self.module_refs.ref_for_fs(test_file) else {
+                continue;
+            };
+            for &src in &changed {
+                let Some(from) = self.module_refs.ref_for_fs(src) else {
+                    continue;
+                };
+                // the test imports (transitively) the changed file, so the import
+                // chain runs test -> ... -> changed file; flip it to read outward
+                if let Some(mut path) = self.dependency_path_between(to, from) {
+                    path.reverse();
+                    explained.insert(test_file, path);
+                    break;
+                }
             }
         }
-        Some(deps)
+        explained
     }
 
     pub fn affected_by_modules<T: AsRef<str>, L: IntoIterator<Item = T>>(
@@ -263,54 +653,48 @@ impl TransitiveClosure {
         )
     }
 
-    fn as_concrete<Fout>(&self, all_sccs: CondensedEdges, f_out: Fout) -> UstrSet
+    fn as_concrete<Fout>(&self, affected: HashSet<ModuleRef>, f_out: Fout) -> UstrSet
     where
         Fout: Fn(&ModuleRefVal) -> Ustr,
     {
-        let mut affected: UstrSet = UstrSet::default();
-        all_sccs.iter().traverse(|c| {
-            for &v in &self.condensed_to_mod[c] {
-                let rv = self.module_refs.get(v);
-                affected.insert(f_out(&rv));
-            }
-            ControlFlow::Continue(())
-        });
         affected
+            .into_iter()
+            .map(|v| f_out(&self.module_refs.get(v)))
+            .collect()
     }
 
     fn as_concrete_pkg_grouped<Fout>(
         &self,
-        all_sccs: CondensedEdges,
+        affected: HashSet<ModuleRef>,
         f_out: Fout,
     ) -> HashMap<Ustr, UstrSet>
     where
         Fout: Fn(&ModuleRefVal) -> Ustr,
     {
         let mut grouped_by_pkg: HashMap<Ustr, UstrSet> = HashMap::new();
-
-        all_sccs.iter().traverse(|c| {
-            for &v in &self.condensed_to_mod[c] {
-                let rv = self.module_refs.get(v);
-                // NB: filter out non-test
-                if rv.pkg.is_some() {
-                    grouped_by_pkg
-                        .entry(rv.pkg.unwrap())
-                        .or_default()
-                        .insert(f_out(&rv));
-                }
+        for v in affected {
+            let rv = self.module_refs.get(v);
+            // NB: filter out non-test
+            if let Some(pkg) = rv.pkg {
+                grouped_by_pkg.entry(pkg).or_default().insert(f_out(&rv));
             }
-            ControlFlow::Continue(())
-        });
+        }
         grouped_by_pkg
     }
 
-    fn affected_by<T, L, Fin>(&self, l: L, f_in: Fin) -> CondensedEdges
+    /// Union of `reaches_set(m)` for every resolved module in `l`, i.e. every module
+    /// that (transitively) depends on one of them. Delegates to `reaches_set` rather
+    /// than indexing `self.ancestor` directly so that it stays correct across
+    /// incremental updates: `reaches_set` is dirty-aware and falls back to a
+    /// brute-force recompute for any module touched by `apply_update`/`grow_for`,
+    /// while `self.ancestor` itself is only patched for the non-cycle-creating case
+    fn affected_by<T, L, Fin>(&self, l: L, f_in: Fin) -> HashSet<ModuleRef>
     where
         T: AsRef<str>,
         L: IntoIterator<Item = T>,
         Fin: Fn(&str) -> Option<ModuleRef>,
     {
-        let mut all_sccs: CondensedEdges = CondensedEdges::new();
+        let mut affected: HashSet<ModuleRef> = HashSet::new();
         for module in l {
             let module = module.as_ref();
             match f_in(module) {
@@ -319,25 +703,195 @@ impl TransitiveClosure {
                     continue;
                 }
                 Some(module_ref) => {
-                    match self
-                        .ancestor
-                        .get(self.mod_to_condensed[module_ref as usize])
-                    {
-                        None => {
-                            // eprintln!("0 tests affected by: {}", modified_file);
-                        }
-                        Some(scc) => {
-                            // eprintln!("{} tests affected by: {}", modified_file, scc.len());
-                            scc.iter().traverse(|e| {
-                                all_sccs.insert(e);
-                                ControlFlow::Continue(())
-                            });
-                        }
+                    let mut reaches = self.reaches_set(module_ref);
+                    // `reaches_set` always includes `module_ref` itself; it only counts
+                    // as affected by itself if one of its direct deps leads back to it
+                    let in_cycle = self
+                        .direct_edges
+                        .get(&module_ref)
+                        .is_some_and(|deps| deps.iter().any(|d| reaches.contains(d)));
+                    if !in_cycle {
+                        reaches.remove(&module_ref);
+                    }
+                    affected.extend(reaches);
+                }
+            }
+        }
+        affected
+    }
+
+    /// Fold the edge delta produced by `graph::ModuleGraph::update_files` into this
+    /// closure, mending `successor`/`ancestor` in place where possible and falling
+    /// back to `mark_dirty` everywhere else, so a caller doing a full `finalize()`
+    /// per edit doesn't have to. `refs` must be a snapshot of the `ModuleRefCache`
+    /// taken after the re-parse that produced `diff`, so that any brand-new
+    /// `ModuleRef` has a slot to grow into
+    pub fn apply_update(&mut self, diff: GraphEdgeDiff, refs: ModuleRefCache) {
+        self.grow_for(refs.max_value());
+        self.module_refs = refs;
+        self.unresolved = diff.unresolved;
+
+        for m in diff.removed_nodes {
+            if let Some(edges) = self.direct_edges.remove(&m) {
+                for t in edges {
+                    self.mark_dirty(t);
+                }
+            }
+            self.mark_dirty(m);
+        }
+        for (s, t) in diff.removed {
+            if let Some(edges) = self.direct_edges.get_mut(&s) {
+                edges.remove(&t);
+            }
+            self.mark_dirty(s);
+            self.mark_dirty(t);
+        }
+        for (s, t) in diff.inserted {
+            self.direct_edges.entry(s).or_default().insert(t);
+            self.insert_edge(s, t);
+        }
+    }
+
+    /// Grow the condensed arrays to cover every `ModuleRef` up to (but excluding)
+    /// `new_max`, registering each new one as its own singleton, edge-less SCC and
+    /// marking it dirty, since it has no real condensed structure until the next
+    /// full `finalize`/`snapshot`
+    fn grow_for(&mut self, new_max: ModuleRef) {
+        while (self.mod_to_condensed.len() as ModuleRef) < new_max {
+            let m = self.mod_to_condensed.len() as ModuleRef;
+            let c = self.condensed_to_mod.len();
+            self.mod_to_condensed.push(c);
+            self.condensed_to_mod.push(CondensedNode::from([m]));
+            self.successor.push(CondensedEdges::new());
+            self.ancestor.push(CondensedEdges::new());
+            self.dirty.borrow_mut().insert(m);
+        }
+    }
+
+    /// Patch the condensed closure in place for a direct edge `s -> t` that didn't
+    /// already exist, generalizing `apply_trigger`'s leaf-only reachability
+    /// propagation to an arbitrary trigger node: every ancestor of `s` (`s`
+    /// included) gains `t`'s descendant set (`t` included). Falls back to
+    /// `mark_dirty` when `t` can already reach `s`, since inserting the edge would
+    /// then merge their components into a single SCC, which isn't attempted here
+    fn insert_edge(&mut self, s: ModuleRef, t: ModuleRef) {
+        let cs = self.mod_to_condensed[s as usize];
+        let ct = self.mod_to_condensed[t as usize];
+        if cs == ct {
+            return;
+        }
+        if self.successor[ct].contains(cs) {
+            self.mark_dirty(s);
+            self.mark_dirty(t);
+            return;
+        }
+
+        let mut new_reachable: Vec<CondensedRef> = self.successor[ct].iter().collect();
+        new_reachable.push(ct);
+
+        let mut cs_ancestors: Vec<CondensedRef> = self.ancestor[cs].iter().collect();
+        cs_ancestors.push(cs);
+
+        for &a in &cs_ancestors {
+            for &d in &new_reachable {
+                self.successor[a].insert(d);
+            }
+        }
+        for &d in &new_reachable {
+            for &a in &cs_ancestors {
+                self.ancestor[d].insert(a);
+            }
+        }
+    }
+
+    /// Mark `m` and all of its current ancestors as dirty, so `depends_on` falls
+    /// back to a memoized DFS over `direct_edges` for any of them instead of
+    /// trusting the (now possibly stale) condensed closure. Used for edge/file
+    /// deletions: unlike insertion, a removed edge can only shrink reachability,
+    /// and working out exactly how far its effect propagates would require
+    /// recomputing SCCs from scratch, which is exactly what this avoids doing eagerly
+    fn mark_dirty(&self, m: ModuleRef) {
+        let mut dirty = self.dirty.borrow_mut();
+        let mut reachable = self.dirty_reachable.borrow_mut();
+        if !dirty.insert(m) {
+            return;
+        }
+        reachable.remove(&m);
+        if let Some(&c) = self.mod_to_condensed.get(m as usize) {
+            for a in &self.ancestor[c] {
+                for &v in &self.condensed_to_mod[a] {
+                    if dirty.insert(v) {
+                        reachable.remove(&v);
                     }
                 }
             }
         }
-        all_sccs
+    }
+
+    /// Recompute the set of modules reachable from `m` via a plain DFS over
+    /// `direct_edges`, for `depends_on`'s dirty fallback. `m` itself is only
+    /// included if it's actually part of a cycle reachable from itself, matching
+    /// the condensed closure's own convention (see `stack_tc`)
+    fn recompute_reachable(&self, m: ModuleRef) -> HashSet<ModuleRef> {
+        let mut visited: HashSet<ModuleRef> = HashSet::from([m]);
+        let mut reachable: HashSet<ModuleRef> = HashSet::new();
+        let mut stack = vec![m];
+        while let Some(node) = stack.pop() {
+            if let Some(edges) = self.direct_edges.get(&node) {
+                for &w in edges {
+                    if w == m {
+                        reachable.insert(m);
+                    }
+                    if visited.insert(w) {
+                        reachable.insert(w);
+                        stack.push(w);
+                    }
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Verify (via `debug_assert!`, compiled out in release builds) that this
+    /// closure's `depends_on` answers agree with a full from-scratch rebuild of the
+    /// same direct-edge graph, for every known module. Intended for exercising
+    /// `apply_update`: after an incremental update, call this to catch any
+    /// divergence from what a full `finalize()` over the same edges would produce
+    #[cfg(debug_assertions)]
+    pub fn debug_assert_matches_rebuild(&self) {
+        let g: DashMap<ModuleRef, HashSet<ModuleRef>> = DashMap::new();
+        for (&m, deps) in &self.direct_edges {
+            g.insert(m, deps.clone());
+        }
+        let rebuilt = TransitiveClosure::from(
+            &g,
+            self.module_refs.clone(),
+            self.unresolved.clone(),
+            self.entry_points.clone(),
+        );
+        for m in 0..self.module_refs.max_value() {
+            let got = self.depends_on(m);
+            let want = rebuilt.depends_on(m);
+            debug_assert_eq!(
+                got,
+                want,
+                "incremental update diverged from rebuild for {}",
+                self.module_refs.py_for_ref(m)
+            );
+
+            // also cross-check the reverse direction (`affected_by`'s building
+            // block): `depends_on` alone wouldn't have caught chunk5-1's bug, since
+            // that one was specific to `affected_by` reading `self.ancestor` without
+            // consulting `self.dirty`
+            let got = self.reaches_set(m);
+            let want = rebuilt.reaches_set(m);
+            debug_assert_eq!(
+                got,
+                want,
+                "incremental update diverged from rebuild for reaches_set({})",
+                self.module_refs.py_for_ref(m)
+            );
+        }
     }
 }
 
@@ -625,6 +1179,23 @@ where
                 w.write_u64_varint(r as u64)?;
             }
         }
+
+        let n = self.entry_points.len();
+        write_length_u64_varint(n, w)?;
+        for (&pkg, &m) in &self.entry_points {
+            write_ustr_to(pkg, w)?;
+            w.write_u64_varint(m as u64)?;
+        }
+
+        let n = self.direct_edges.len();
+        write_length_u64_varint(n, w)?;
+        for (&m, deps) in &self.direct_edges {
+            w.write_u64_varint(m as u64)?;
+            write_length_u64_varint(deps.len(), w)?;
+            for &d in deps {
+                w.write_u64_varint(d as u64)?;
+            }
+        }
         Ok(())
     }
 }
@@ -682,6 +1253,26 @@ where
             unresolved.insert(m, modules);
         }
 
+        let n = read_length_u64_varint(reader)?;
+        let mut entry_points = HashMap::with_capacity(n);
+        for _ in 0..n {
+            let pkg = read_ustr_with_buf(reader, &mut buf)?;
+            let m = reader.read_u64_varint()? as ModuleRef;
+            entry_points.insert(pkg, m);
+        }
+
+        let n = read_length_u64_varint(reader)?;
+        let mut direct_edges = HashMap::with_capacity(n);
+        for _ in 0..n {
+            let m = reader.read_u64_varint()? as ModuleRef;
+            let l = read_length_u64_varint(reader)?;
+            let mut deps = HashSet::with_capacity(l);
+            for _ in 0..l {
+                deps.insert(reader.read_u64_varint()? as ModuleRef);
+            }
+            direct_edges.insert(m, deps);
+        }
+
         Ok(TransitiveClosure {
             module_refs,
             mod_to_condensed,
@@ -689,6 +1280,91 @@ where
             successor,
             ancestor,
             unresolved,
+            entry_points,
+            direct_edges,
+            dirty: RefCell::new(HashSet::new()),
+            dirty_reachable: RefCell::new(HashMap::new()),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::GraphEdgeDiff;
+
+    /// d -> a -> b -> c, then incrementally insert c -> a: `insert_edge` takes the
+    /// cycle-merging fallback (a already reaches c, so the new edge can't be patched
+    /// in place) and only `mark_dirty`s the endpoints, relying on every reachability
+    /// accessor to consult `self.dirty` afterwards. Regression test for `affected_by`
+    /// having skipped that check and reporting a stale, too-small result
+    #[test]
+    fn affected_by_reflects_cycle_created_by_incremental_update() {
+        let locked = LockedModuleRefCache::new();
+        let a = locked.get_or_create(ustr("/a.py"), ustr("a"), None);
+        let b = locked.get_or_create(ustr("/b.py"), ustr("b"), None);
+        let c = locked.get_or_create(ustr("/c.py"), ustr("c"), None);
+        let d = locked.get_or_create(ustr("/d.py"), ustr("d"), None);
+
+        let g: DashMap<ModuleRef, HashSet<ModuleRef>> = DashMap::new();
+        g.insert(d, HashSet::from([a]));
+        g.insert(a, HashSet::from([b]));
+        g.insert(b, HashSet::from([c]));
+
+        let mut tc = TransitiveClosure::from(&g, locked.snapshot(), HashMap::new(), HashMap::new());
+
+        // before the update, only `d` depends on `a`
+        assert_eq!(tc.affected_by_modules(["a"]), UstrSet::from_iter([ustr("d")]));
+
+        let diff = GraphEdgeDiff {
+            inserted: vec![(c, a)],
+            removed: vec![],
+            removed_nodes: vec![],
+            unresolved: HashMap::new(),
+        };
+        tc.apply_update(diff, locked.snapshot());
+
+        // `a`, `b` and `c` just merged into a single cycle, and `d` still depends on
+        // all of it: every one of them must now show up as affected by `a`
+        assert_eq!(
+            tc.affected_by_modules(["a"]),
+            UstrSet::from_iter([ustr("a"), ustr("b"), ustr("c"), ustr("d")])
+        );
+
+        tc.debug_assert_matches_rebuild();
+    }
+
+    /// s -> t, then incrementally remove that edge: `apply_update` must `mark_dirty`
+    /// not just the edge's source but its target too, otherwise `affected_by_modules`
+    /// queried against `t` keeps trusting the stale `ancestor` bitset and still
+    /// reports `s` as affected after the edge is gone. Regression test for that gap
+    /// slipping past the insertion-only `affected_by_reflects_cycle_created_by_incremental_update`
+    #[test]
+    fn affected_by_reflects_edge_removed_by_incremental_update() {
+        let locked = LockedModuleRefCache::new();
+        let s = locked.get_or_create(ustr("/s.py"), ustr("s"), None);
+        let t = locked.get_or_create(ustr("/t.py"), ustr("t"), None);
+
+        let g: DashMap<ModuleRef, HashSet<ModuleRef>> = DashMap::new();
+        g.insert(s, HashSet::from([t]));
+
+        let mut tc = TransitiveClosure::from(&g, locked.snapshot(), HashMap::new(), HashMap::new());
+
+        // before the update, `s` depends on `t`, so `t` is affected by `s`
+        assert_eq!(tc.affected_by_modules(["t"]), UstrSet::from_iter([ustr("s")]));
+
+        let diff = GraphEdgeDiff {
+            inserted: vec![],
+            removed: vec![(s, t)],
+            removed_nodes: vec![],
+            unresolved: HashMap::new(),
+        };
+        tc.apply_update(diff, locked.snapshot());
+
+        // the edge is gone: `s` no longer depends on `t`, so `t` must no longer
+        // report `s` as affected
+        assert_eq!(tc.affected_by_modules(["t"]), UstrSet::new());
+
+        tc.debug_assert_matches_rebuild();
+    }
+}
@@ -4,13 +4,14 @@ use log::ParseLevelError;
 use pyo3::exceptions::{PyException, PyTypeError};
 use pyo3::marker::Ungil;
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyNone, PySequence, PySet, PyString};
+use pyo3::types::{PyDict, PyList, PyNone, PySequence, PySet, PyString};
 use pyo3::IntoPyObjectExt;
 use std::collections::{HashMap, HashSet};
 use ustr::{Ustr, UstrSet};
 
 use common::graph;
 use common::parser;
+use common::stdlib;
 use common::transitive_closure::TransitiveClosure;
 
 fn to_vec<'py, T>(v: Bound<'py, PyAny>) -> PyResult<Vec<T>>
@@ -34,6 +35,10 @@ where
 
 #[pyclass(subclass, module = "prunepytest")]
 pub struct ModuleGraph {
+    // only present when built from source (`new`): re-parseable, so `update_files`
+    // has something to incrementally re-scan. A graph loaded via `from_file` has no
+    // source-root/prefix configuration to re-parse with, so it stays `None`
+    g: Option<graph::ModuleGraph>,
     tc: TransitiveClosure,
 }
 
@@ -42,55 +47,144 @@ impl ModuleGraph {
     #[new]
     #[pyo3(signature = (source_roots, global_prefixes, local_prefixes,
                         external_prefixes=HashSet::default(),
+                        stdlib_modules=HashSet::default(),
                         dynamic_deps=HashMap::default(),
+                        include_typechecking=false,
     ))]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         py: Python<'_>,
         source_roots: HashMap<String, String>,
         global_prefixes: HashSet<String>,
         local_prefixes: HashSet<String>,
         external_prefixes: HashSet<String>,
+        stdlib_modules: HashSet<String>,
         dynamic_deps: HashMap<String, HashSet<String>>,
+        include_typechecking: bool,
     ) -> PyResult<ModuleGraph> {
-        let tc = py
+        let (g, tc) = py
             .allow_threads(|| {
                 let g = graph::ModuleGraph::new(
                     source_roots,
                     global_prefixes,
                     local_prefixes,
                     external_prefixes,
+                    stdlib_modules,
                 );
-                g.parse_parallel()?;
+                g.parse_parallel(include_typechecking)?;
                 if !dynamic_deps.is_empty() {
                     g.add_dynamic_dependencies(dynamic_deps);
                 }
-                Ok(g.finalize())
+                let tc = g.snapshot();
+                Ok::<_, anyhow::Error>((g, tc))
             })
-            .map_err(|e: parser::Error| PyErr::new::<PyException, _>(e.to_string()))?;
-        Ok(ModuleGraph { tc })
+            .map_err(|e: anyhow::Error| PyErr::new::<PyException, _>(e.to_string()))?;
+        Ok(ModuleGraph { g: Some(g), tc })
     }
 
     #[staticmethod]
     #[pyo3(signature = (filepath))]
     fn from_file(py: Python<'_>, filepath: &str) -> PyResult<ModuleGraph> {
         Ok(ModuleGraph {
+            g: None,
             tc: py
                 .allow_threads(|| TransitiveClosure::from_file(filepath))
                 .map_err(|e| PyErr::new::<PyException, _>(e.to_string()))?,
         })
     }
 
+    /// Re-parse `changed` (and drop `deleted`) instead of rebuilding the whole
+    /// graph, incrementally mending the closure in place. Only available on a
+    /// graph built via the constructor: one loaded from `from_file` has no
+    /// source-root/prefix configuration left to re-parse with
+    #[pyo3(signature = (changed, deleted, include_typechecking=false))]
+    fn update_files(
+        &mut self,
+        py: Python<'_>,
+        changed: Vec<String>,
+        deleted: Vec<String>,
+        include_typechecking: bool,
+    ) -> PyResult<()> {
+        if self.g.is_none() {
+            return Err(PyErr::new::<PyException, _>(
+                "update_files requires a graph built from source, not one loaded via from_file",
+            ));
+        }
+        py.allow_threads(|| {
+            let g = self.g.as_ref().unwrap();
+            let diff = g.update_files(&changed, &deleted, include_typechecking);
+            let refs = g.snapshot_module_refs();
+            self.tc.apply_update(diff, refs);
+            #[cfg(debug_assertions)]
+            self.tc.debug_assert_matches_rebuild();
+        });
+        Ok(())
+    }
+
     #[pyo3(signature = ())]
     fn unresolved(&self) -> PyResult<HashMap<String, HashSet<String>>> {
         Ok(self.tc.unresolved())
     }
 
+    /// Every import cycle among local modules, each as an ordered list of import
+    /// paths. Only available on a graph built from source, not one loaded via
+    /// `from_file`/`from_text_file`: those retain no `global_ns` to walk
+    #[pyo3(signature = ())]
+    fn import_cycles(&self, py: Python<'_>) -> PyResult<Vec<Vec<String>>> {
+        let g = self.g.as_ref().ok_or_else(|| {
+            PyErr::new::<PyException, _>(
+                "import_cycles requires a graph built from source, not one loaded via from_file",
+            )
+        })?;
+        Ok(py.allow_threads(|| g.find_import_cycles()))
+    }
+
     #[pyo3(signature = (filepath))]
     fn to_file(&self, py: Python<'_>, filepath: &str) -> PyResult<()> {
         py.allow_threads(|| self.tc.to_file(filepath))
             .map_err(|e| PyErr::new::<PyException, _>(e.to_string()))
     }
 
+    /// Dump a deterministic, human-readable text format suitable for committing a
+    /// baseline graph in CI: a graph loaded via `from_file`/`from_text_file` has no
+    /// source-root/prefix configuration left to report, so its header is empty
+    #[pyo3(signature = (filepath))]
+    fn to_text_file(&self, py: Python<'_>, filepath: &str) -> PyResult<()> {
+        let (source_roots, global_prefixes, local_prefixes, external_prefixes, stdlib_modules) =
+            match &self.g {
+                Some(g) => (
+                    g.source_roots().clone(),
+                    g.global_prefixes().clone(),
+                    g.local_prefixes().clone(),
+                    g.external_prefixes(),
+                    g.stdlib_modules(),
+                ),
+                None => Default::default(),
+            };
+        py.allow_threads(|| {
+            self.tc.to_text_file(
+                filepath,
+                &source_roots,
+                &global_prefixes,
+                &local_prefixes,
+                &external_prefixes,
+                &stdlib_modules,
+            )
+        })
+        .map_err(|e| PyErr::new::<PyException, _>(e.to_string()))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (filepath))]
+    fn from_text_file(py: Python<'_>, filepath: &str) -> PyResult<ModuleGraph> {
+        Ok(ModuleGraph {
+            g: None,
+            tc: py
+                .allow_threads(|| TransitiveClosure::from_text_file(filepath))
+                .map_err(|e| PyErr::new::<PyException, _>(e.to_string()))?,
+        })
+    }
+
     #[pyo3(signature = (simple_unified, simple_per_package))]
     fn add_dynamic_dependencies_at_edges(
         &mut self,
@@ -138,6 +232,27 @@ impl ModuleGraph {
         }
     }
 
+    /// Transitive dependency set of the `__main__.py` entry-point owned by
+    /// `pkg_import_path` (pass `""` for a top-level script), mirroring
+    /// `module_depends_on` but keyed by owning package rather than module path
+    #[pyo3(signature = (pkg_import_path))]
+    fn entry_point_depends_on<'py>(
+        &self,
+        py: Python<'py>,
+        pkg_import_path: &str,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        match self.tc.entry_point_depends_on(pkg_import_path) {
+            None => PyNone::get(py).into_bound_py_any(py),
+            Some(deps) => {
+                let r = PySet::empty(py)?;
+                for dep in &deps {
+                    r.add(PyString::new(py, dep))?;
+                }
+                r.into_bound_py_any(py)
+            }
+        }
+    }
+
     #[pyo3(signature = (files))]
     fn affected_by_files<'py>(
         &self,
@@ -156,6 +271,37 @@ impl ModuleGraph {
         affected_by(py, modules, |l| self.tc.affected_by_modules(l))
     }
 
+    #[pyo3(signature = (from_module, to_module))]
+    fn dependency_path<'py>(
+        &self,
+        py: Python<'py>,
+        from_module: &str,
+        to_module: &str,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        match self.tc.dependency_path(from_module, to_module) {
+            None => PyNone::get(py).into_bound_py_any(py),
+            Some(path) => PyList::new(py, path.iter().map(|m| PyString::new(py, m)))?
+                .into_bound_py_any(py),
+        }
+    }
+
+    #[pyo3(signature = (files))]
+    fn explain_affected_by_files<'py>(
+        &self,
+        py: Python<'py>,
+        files: Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let files: Vec<String> = to_vec(files)?;
+        let explained = py.allow_threads(|| self.tc.explain_affected_by_files(files));
+
+        let r = PyDict::new(py);
+        for (test_file, path) in &explained {
+            let path = PyList::new(py, path.iter().map(|m| PyString::new(py, m)))?;
+            r.set_item(PyString::new(py, test_file), path)?
+        }
+        Ok(r)
+    }
+
     #[pyo3(signature = (files))]
     fn local_affected_by_files<'py>(
         &self,
@@ -234,10 +380,17 @@ fn file_looks_like_pkgutil_ns_init(file: String) -> PyResult<bool> {
         .map_err(|e| PyErr::new::<PyException, _>(e.to_string()))
 }
 
+#[pyfunction]
+#[pyo3(signature = (stdlib_dir=None))]
+fn list_stdlib_modules(stdlib_dir: Option<String>) -> PyResult<HashSet<String>> {
+    Ok(stdlib::list_stdlib_modules(stdlib_dir.as_deref()))
+}
+
 #[pymodule]
 fn _prunepytest(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<ModuleGraph>()?;
     m.add_function(wrap_pyfunction!(configure_logger, m)?)?;
     m.add_function(wrap_pyfunction!(file_looks_like_pkgutil_ns_init, m)?)?;
+    m.add_function(wrap_pyfunction!(list_stdlib_modules, m)?)?;
     Ok(())
 }